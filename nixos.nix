@@ -1,5 +1,6 @@
 { pkgs, options, config, lib, utils, inputs'systemd2mqtt, ... }: with lib; let
   cfg = config.services.systemd2mqtt;
+  settingsFormat = pkgs.formats.json { };
   StateDirectory = "systemd2mqtt";
   WorkingDirectory = "/var/lib/${StateDirectory}";
   tos = value:
@@ -72,6 +73,14 @@ in {
       type = listOf str;
       default = [ ];
     };
+    settings = mkOption {
+      type = settingsFormat.type;
+      default = { };
+    };
+    configFile = mkOption {
+      type = nullOr path;
+      default = if cfg.settings != { } then settingsFormat.generate "systemd2mqtt.json" cfg.settings else null;
+    };
   };
   config = mkMerge [
     {
@@ -85,6 +94,7 @@ in {
         ${if cfg.hostName != null then "hostname" else null} = cfg.hostName;
         unit = mapAttrsToList (_: unit: unit.arg) cfg.units;
         ${if cfg.mqtt.username != null then "mqtt-username" else null} = cfg.mqtt.username;
+        ${if cfg.configFile != null then "config" else null} = cfg.configFile;
       };
     }
     (mkIf cfg.enable {
@@ -97,6 +107,7 @@ in {
           inherit WorkingDirectory StateDirectory;
           User = mkDefault cfg.user;
           ExecStart = singleton "${getExe cfg.package} ${utils.escapeSystemdExecArgs cfg.extraArgs}";
+          ExecReload = "${pkgs.coreutils}/bin/kill -HUP $MAINPID";
           Restart = mkDefault "on-failure";
           Environment = [
             "RUST_LOG=${cfg.logLevel}"
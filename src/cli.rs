@@ -1,11 +1,21 @@
 use {
+	crate::{config::RunTemplate, manager::Scope},
 	anyhow::Error,
 	clap::Parser,
 	hass_mqtt_discovery::{DeviceClass, EntityCategory},
 	once_cell::sync::Lazy,
 	paho_mqtt as mqtt,
+	regex::Regex,
 	serde::{Deserialize, Serialize},
-	std::{borrow::Cow, collections::HashMap, ops::Deref, str::FromStr},
+	std::{
+		borrow::Cow,
+		collections::{BTreeMap, HashMap},
+		convert::TryFrom,
+		fmt,
+		ops::Deref,
+		path::PathBuf,
+		str::FromStr,
+	},
 	url::Url,
 };
 
@@ -18,14 +28,32 @@ pub struct Args {
 	#[arg(short = 'M', long)]
 	pub mqtt_url: Option<Url>,
 	/// units to pay attention to
-	///
-	/// A unit can be specified with additional settings,
-	/// for example: `display-manager.service?read-only=true&icon=mdi:projector-screen`
-	#[arg(short, long = "unit")]
-	pub units: Vec<UnitConfig>,
+	#[command(flatten)]
+	pub selection: UnitSelection,
 	/// identify this host
 	#[arg(short = 'H', long)]
 	pub hostname: Option<String>,
+	/// talk to the service manager of this user session rather than the system
+	///
+	/// Units may also pick their manager individually, such as `mpd.service?manager=user`
+	#[arg(long)]
+	pub user: bool,
+	/// follow the service managers of every logged-in user through logind
+	///
+	/// Units with `manager=user` are then exposed once for each user, each as their own device.
+	#[arg(long, conflicts_with("user"))]
+	pub users: bool,
+	/// also follow containers registered with systemd-machined
+	///
+	/// System units are then exposed once for each container, each as their own device.
+	#[arg(long, conflicts_with("user"))]
+	pub machines: bool,
+	/// expose buttons to reboot, power off, or suspend the host through logind
+	#[arg(long)]
+	pub power: bool,
+	/// targets to switch between through a select entity, such as `graphical.target`
+	#[arg(long = "isolate-target")]
+	pub isolate_targets: Vec<String>,
 	/// MQTT client ID
 	#[arg(short, long)]
 	pub client_id: Option<String>,
@@ -41,6 +69,27 @@ pub struct Args {
 	/// authentication password
 	#[arg(short = 'P', long, env("MQTT_PASSWORD"))]
 	pub mqtt_password: Option<String>,
+	/// read settings and units from a TOML, YAML, or JSON file
+	///
+	/// Options given on the command line or through the environment take precedence,
+	/// and any units are added to those from the file.
+	#[arg(short = 'C', long, env("SYSTEMD2MQTT_CONFIG"))]
+	pub config: Option<PathBuf>,
+	/// connect with MQTT v5, which lets commands ask for their outcome through a `ResponseTopic`
+	#[arg(long)]
+	pub mqtt5: bool,
+	/// also take units from the retained `config` topic, on top of any configured locally
+	#[arg(long)]
+	pub mqtt_config: bool,
+	/// let the control topic track and untrack units, along with whatever settings it gives them
+	#[arg(long)]
+	pub mqtt_track: bool,
+	/// where to remember units tracked through the control topic
+	#[arg(long, env("STATE_DIRECTORY"))]
+	pub state_directory: Option<PathBuf>,
+	/// commands that may be run through the control topic, only read from the configuration file
+	#[arg(skip)]
+	pub run_templates: BTreeMap<String, RunTemplate>,
 }
 
 #[derive(Debug, Clone, Serialize, Deserialize)]
@@ -64,6 +113,288 @@ pub struct UnitConfig {
 	pub object_id: Option<String>,
 	#[serde(default, skip_serializing_if = "DeviceClass::is_none")]
 	pub device_class: DeviceClass,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub instances: Option<Instances>,
+	/// a regex that instances named with `instances=payload` must match in full
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub instance_pattern: Option<InstancePattern>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub manager: Option<ManagerKind>,
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub job_mode: Option<JobMode>,
+	/// other modes that commands may ask for, such as `job-modes=replace,isolate`
+	#[serde(
+		default,
+		deserialize_with = "crate::config::deserialize_list",
+		skip_serializing_if = "Vec::is_empty"
+	)]
+	pub job_modes: Vec<JobMode>,
+	/// also expose whether the unit starts on boot, such as `enable=switch`
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub enable: Option<EnableEntity>,
+	/// expose masking the unit as a lock, such as `mask=runtime` to be undone on reboot
+	#[serde(default, skip_serializing_if = "Option::is_none")]
+	pub mask: Option<MaskMode>,
+	/// expose freezing the unit's processes as a `paused` switch
+	#[serde(alias = "freeze", default)]
+	pub pause: bool,
+	/// directories that may be removed while the unit is stopped, such as `clean=cache,logs`,
+	/// exposed as a button
+	#[serde(
+		default,
+		deserialize_with = "crate::config::deserialize_list",
+		skip_serializing_if = "Vec::is_empty"
+	)]
+	pub clean: Vec<CleanMask>,
+	/// resource controls to expose as numbers, such as `properties=cpu-quota,memory-high`
+	#[serde(
+		default,
+		deserialize_with = "crate::config::deserialize_list",
+		skip_serializing_if = "Vec::is_empty"
+	)]
+	pub properties: Vec<ResourceProperty>,
+	/// keep changes to `properties` across reboots
+	#[serde(default)]
+	pub persist_properties: bool,
+}
+
+/// The units of interest, which unlike other settings may change at runtime
+#[deny(missing_docs)]
+#[derive(clap::Args, Debug, Clone, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct UnitSelection {
+	/// units to pay attention to
+	///
+	/// A unit can be specified with additional settings,
+	/// for example: `display-manager.service?read-only=true&icon=mdi:projector-screen`
+	///
+	/// Templates such as `getty@.service` (or `?instances=all`) expose every loaded instance,
+	/// and `%i` in `name` or `object-id` is replaced with the instance string.
+	/// With `?instances=payload`, commands instead name the instance to act on.
+	#[arg(short, long = "unit")]
+	#[serde(deserialize_with = "crate::config::deserialize_units")]
+	pub units: Vec<UnitConfig>,
+	/// unit name patterns to pay attention to
+	///
+	/// Shell-style globs are matched against loaded units and installed unit files,
+	/// and any settings apply to every match: `backup-*.service?read-only=true`
+	#[arg(short = 'p', long = "unit-pattern")]
+	#[serde(deserialize_with = "crate::config::deserialize_units")]
+	pub unit_patterns: Vec<UnitConfig>,
+}
+
+/// Which kind of systemd manager a unit belongs to
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Hash, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ManagerKind {
+	System,
+	User,
+}
+
+/// How a newly enqueued job interacts with those already queued
+///
+/// See `systemctl(1)`'s `--job-mode`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum JobMode {
+	Replace,
+	Fail,
+	Isolate,
+	IgnoreDependencies,
+	IgnoreRequirements,
+	ReplaceIrreversibly,
+}
+
+impl JobMode {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			JobMode::Replace => "replace",
+			JobMode::Fail => "fail",
+			JobMode::Isolate => "isolate",
+			JobMode::IgnoreDependencies => "ignore-dependencies",
+			JobMode::IgnoreRequirements => "ignore-requirements",
+			JobMode::ReplaceIrreversibly => "replace-irreversibly",
+		}
+	}
+}
+
+impl fmt::Display for JobMode {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		f.write_str(self.as_str())
+	}
+}
+
+/// How a unit's `UnitFileState` is exposed to home-assistant
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum EnableEntity {
+	/// enables and disables the unit file
+	Switch,
+	BinarySensor,
+}
+
+/// Whether a mask outlives a reboot
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum MaskMode {
+	/// masked under `/run`
+	Runtime,
+	Persistent,
+}
+
+impl MaskMode {
+	pub fn is_runtime(&self) -> bool {
+		*self == MaskMode::Runtime
+	}
+}
+
+/// A regex that instances must match in full, checked as the configuration is parsed
+#[derive(Debug, Clone)]
+pub struct InstancePattern {
+	source: String,
+	regex: Regex,
+}
+
+impl InstancePattern {
+	pub fn is_match(&self, instance: &str) -> bool {
+		self.regex.is_match(instance)
+	}
+}
+
+impl TryFrom<String> for InstancePattern {
+	type Error = regex::Error;
+
+	fn try_from(source: String) -> Result<Self, Self::Error> {
+		let regex = Regex::new(&format!("^(?:{})$", source))?;
+		Ok(Self { source, regex })
+	}
+}
+
+impl Serialize for InstancePattern {
+	fn serialize<S: serde::Serializer>(&self, serializer: S) -> Result<S::Ok, S::Error> {
+		self.source.serialize(serializer)
+	}
+}
+
+impl<'de> Deserialize<'de> for InstancePattern {
+	fn deserialize<D: serde::Deserializer<'de>>(deserializer: D) -> Result<Self, D::Error> {
+		Self::try_from(String::deserialize(deserializer)?).map_err(serde::de::Error::custom)
+	}
+}
+
+/// What `CleanUnit` removes, see `systemctl(1)`'s `clean`
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum CleanMask {
+	/// `CacheDirectory=`
+	Cache,
+	/// `StateDirectory=`
+	State,
+	/// `LogsDirectory=`
+	Logs,
+	/// `RuntimeDirectory=`
+	Runtime,
+	/// `ConfigurationDirectory=`
+	Configuration,
+}
+
+impl CleanMask {
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			CleanMask::Cache => "cache",
+			CleanMask::State => "state",
+			CleanMask::Logs => "logs",
+			CleanMask::Runtime => "runtime",
+			CleanMask::Configuration => "configuration",
+		}
+	}
+}
+
+/// Resource controls that may be adjusted at runtime, see `systemd.resource-control(5)`
+///
+/// Values are given in more convenient units than systemd's,
+/// with `0` standing in for `infinity` or the default.
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum ResourceProperty {
+	/// percent of a single CPU
+	CpuQuota,
+	CpuWeight,
+	/// MiB
+	MemoryHigh,
+	/// MiB
+	MemoryMax,
+	IoWeight,
+	TasksMax,
+}
+
+impl ResourceProperty {
+	const MIB: u64 = 1024 * 1024;
+
+	pub fn as_str(&self) -> &'static str {
+		match self {
+			ResourceProperty::CpuQuota => "cpu-quota",
+			ResourceProperty::CpuWeight => "cpu-weight",
+			ResourceProperty::MemoryHigh => "memory-high",
+			ResourceProperty::MemoryMax => "memory-max",
+			ResourceProperty::IoWeight => "io-weight",
+			ResourceProperty::TasksMax => "tasks-max",
+		}
+	}
+
+	/// Identifies the property in the status payload and entity IDs
+	pub fn key(&self) -> &'static str {
+		match self {
+			ResourceProperty::CpuQuota => "cpu_quota",
+			ResourceProperty::CpuWeight => "cpu_weight",
+			ResourceProperty::MemoryHigh => "memory_high",
+			ResourceProperty::MemoryMax => "memory_max",
+			ResourceProperty::IoWeight => "io_weight",
+			ResourceProperty::TasksMax => "tasks_max",
+		}
+	}
+
+	/// The D-Bus property, which `SetUnitProperties` accepts under the same name
+	pub fn dbus_name(&self) -> &'static str {
+		match self {
+			ResourceProperty::CpuQuota => "CPUQuotaPerSecUSec",
+			ResourceProperty::CpuWeight => "CPUWeight",
+			ResourceProperty::MemoryHigh => "MemoryHigh",
+			ResourceProperty::MemoryMax => "MemoryMax",
+			ResourceProperty::IoWeight => "IOWeight",
+			ResourceProperty::TasksMax => "TasksMax",
+		}
+	}
+
+	/// Convert from systemd's units
+	pub fn decode(&self, value: u64) -> u64 {
+		match (self, value) {
+			(_, u64::MAX) => 0,
+			(ResourceProperty::CpuQuota, usec) => usec / 10_000,
+			(ResourceProperty::MemoryHigh | ResourceProperty::MemoryMax, bytes) => bytes / Self::MIB,
+			(_, value) => value,
+		}
+	}
+
+	/// Convert into systemd's units
+	pub fn encode(&self, value: u64) -> u64 {
+		match (self, value) {
+			(_, 0) => u64::MAX,
+			(ResourceProperty::CpuQuota, percent) => percent.saturating_mul(10_000),
+			(ResourceProperty::MemoryHigh | ResourceProperty::MemoryMax, mib) => mib.saturating_mul(Self::MIB),
+			(_, value) => value,
+		}
+	}
+}
+
+/// How a template unit maps onto its instances
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum Instances {
+	/// every loaded instance becomes its own entity
+	All,
+	/// commands name the instance to act on, such as `{"Start": {"instance": "nas"}}`
+	Payload,
 }
 
 impl Args {
@@ -79,8 +410,30 @@ impl Args {
 			})
 	}
 
-	pub fn units(&self) -> HashMap<&str, Unit> {
-		self.units.iter().map(|u| (&u.unit[..], Unit::new(self, u))).collect()
+	pub fn user_name(&self) -> String {
+		std::env::var("USER")
+			.or_else(|_| std::env::var("LOGNAME"))
+			.unwrap_or_else(|_| "user".into())
+	}
+
+	/// The kind of manager that units belong to unless configured otherwise
+	pub fn manager_kind(&self) -> ManagerKind {
+		match self.user {
+			true => ManagerKind::User,
+			false => ManagerKind::System,
+		}
+	}
+
+	/// The manager this instance primarily represents
+	pub fn scope(&self) -> Scope {
+		self.kind_scope(self.manager_kind())
+	}
+
+	pub fn kind_scope(&self, kind: ManagerKind) -> Scope {
+		match kind {
+			ManagerKind::System => Scope::System,
+			ManagerKind::User => Scope::User(self.user_name()),
+		}
 	}
 
 	pub fn use_mqtt(&self) -> bool {
@@ -106,7 +459,19 @@ impl Args {
 	pub fn mqtt_create(&self) -> mqtt::CreateOptionsBuilder {
 		mqtt::CreateOptionsBuilder::new()
 			.server_uri(self.mqtt_url.as_ref().map(|s| &s[..]).unwrap_or_default())
-			.client_id(self.client_id.as_ref().map(|s| &s[..]).unwrap_or("systemd"))
+			.client_id(
+				self
+					.client_id
+					.clone()
+					.unwrap_or_else(|| match self.scope().topic_prefix() {
+						Some(prefix) => format!("systemd-{}", prefix.replace('/', "-")),
+						None => "systemd".into(),
+					}),
+			)
+			.mqtt_version(match self.mqtt5 {
+				true => mqtt::MQTT_VERSION_5,
+				false => mqtt::MQTT_VERSION_DEFAULT,
+			})
 			.persist_qos0(false)
 	}
 
@@ -118,14 +483,28 @@ impl Args {
 		if let Some(pw) = self.mqtt_password() {
 			opts.password(pw);
 		}
-		opts.clean_session(true);
+		match self.mqtt5 {
+			true => opts.clean_start(true),
+			false => opts.clean_session(true),
+		};
 		opts
 	}
 
-	pub fn topic_root(&self) -> String {
+	pub fn host_topic_root(&self) -> String {
 		format!("systemd/{}", self.hostname())
 	}
 
+	pub fn scope_topic_root(&self, scope: &Scope) -> String {
+		match scope.topic_prefix() {
+			Some(prefix) => format!("{}/{}", self.host_topic_root(), prefix),
+			None => self.host_topic_root(),
+		}
+	}
+
+	pub fn topic_root(&self) -> String {
+		self.scope_topic_root(&self.scope())
+	}
+
 	pub fn mqtt_pub_topic(&self) -> String {
 		format!("{}/status", self.topic_root())
 	}
@@ -133,40 +512,249 @@ impl Args {
 	pub fn mqtt_sub_topic(&self) -> String {
 		format!("{}/control", self.topic_root())
 	}
+
+	pub fn mqtt_config_topic(&self) -> String {
+		format!("{}/config", self.topic_root())
+	}
+
+	pub fn mqtt_error_topic(&self) -> String {
+		format!("{}/error", self.topic_root())
+	}
+
+	pub fn mqtt_run_topic(&self) -> String {
+		format!("{}/run", self.topic_root())
+	}
+
+	pub fn mqtt_target_topic(&self) -> String {
+		format!("{}/target", self.topic_root())
+	}
+}
+
+impl UnitSelection {
+	/// Every manager that configured units may belong to, aside from any logind users
+	pub fn scopes(&self, cli: &Args) -> Vec<Scope> {
+		let mut scopes: Vec<_> = self
+			.units
+			.iter()
+			.chain(&self.unit_patterns)
+			.map(|u| u.manager_kind(cli))
+			.filter(|&kind| !(cli.users && kind == ManagerKind::User))
+			.map(|kind| cli.kind_scope(kind))
+			.chain(Some(cli.scope()))
+			.collect();
+		scopes.sort();
+		scopes.dedup();
+		scopes
+	}
+
+	/// Units explicitly configured for a manager
+	pub fn scope_units<'a>(&self, cli: &'a Args, scope: &Scope) -> HashMap<String, Unit<'a>> {
+		self
+			.units
+			.iter()
+			.filter(|u| !u.is_template() && u.manager_kind(cli) == scope.kind())
+			.map(|u| Unit::new(cli, scope.clone(), Cow::Owned(u.clone())))
+			.map(|u| (u.key(), u))
+			.collect()
+	}
+
+	/// Configurations that may match any number of units
+	pub fn unit_selectors<'s>(&'s self, cli: &'s Args, kind: ManagerKind) -> impl Iterator<Item = &'s UnitConfig> {
+		self
+			.unit_patterns
+			.iter()
+			.chain(self.units.iter().filter(|u| u.is_template()))
+			.filter(move |u| u.manager_kind(cli) == kind)
+	}
+
+	pub fn unit_patterns(&self, cli: &Args, kind: ManagerKind) -> Vec<String> {
+		self
+			.unit_selectors(cli, kind)
+			.map(|u| u.pattern().into_owned())
+			.collect()
+	}
+
+	/// Patterns that may match installed unit files, which excludes template instances
+	pub fn unit_file_patterns(&self, cli: &Args, kind: ManagerKind) -> Vec<String> {
+		self
+			.unit_selectors(cli, kind)
+			.filter(|u| !u.is_template())
+			.map(|u| u.pattern().into_owned())
+			.collect()
+	}
+
+	/// Layer another selection over this one, with its units and patterns taking precedence
+	pub fn with_selection(&self, other: &Self) -> Self {
+		Self {
+			units: self.units.iter().chain(&other.units).cloned().collect(),
+			unit_patterns: other.unit_patterns.iter().chain(&self.unit_patterns).cloned().collect(),
+		}
+	}
+
+	/// Include additional units, which take precedence over any already selected
+	pub fn with_units(&self, units: &[UnitConfig]) -> Self {
+		let mut selection = self.clone();
+		selection.units.extend(units.iter().cloned());
+		selection
+	}
+
+	/// Whether a unit was requested by name rather than discovered through a pattern
+	pub fn is_explicit(&self, cli: &Args, scope: &Scope, name: &str) -> bool {
+		self
+			.units
+			.iter()
+			.any(|u| u.unit == name && u.manager_kind(cli) == scope.kind())
+	}
 }
 
 impl UnitConfig {
+	/// Settings for a concrete unit matched by this configuration's pattern
+	pub fn with_unit<U: Into<String>>(&self, unit: U) -> Self {
+		Self {
+			unit: unit.into(),
+			..self.clone()
+		}
+	}
+
+	/// Match a unit name against this configuration's glob pattern
+	pub fn matches(&self, unit: &str) -> bool {
+		glob_match(self.pattern().as_bytes(), unit.as_bytes())
+	}
+
+	/// The glob this configuration selects units with
+	pub fn pattern(&self) -> Cow<'_, str> {
+		match self.is_template() {
+			true => self
+				.template()
+				.map(|(prefix, suffix)| format!("{prefix}@*.{suffix}").into())
+				.unwrap_or(Cow::Borrowed(&self.unit)),
+			false => Cow::Borrowed(&self.unit),
+		}
+	}
+
+	/// Whether this refers to all instances of a template unit rather than a single unit
+	pub fn is_template(&self) -> bool {
+		match self.template() {
+			Some(..) if self.is_payload_template() => false,
+			Some(..) => self.instances == Some(Instances::All) || self.instance() == Some(""),
+			None => false,
+		}
+	}
+
+	/// Whether this is a template whose commands name the instance to act on
+	pub fn is_payload_template(&self) -> bool {
+		self.instances == Some(Instances::Payload) && self.instance() == Some("")
+	}
+
+	/// The unit a command's instance refers to, after escaping and checking it against
+	/// `instance-pattern`
+	pub fn payload_instance(&self, instance: &str) -> Result<String, Error> {
+		let (prefix, suffix) = match self.template() {
+			Some(template) if self.is_payload_template() => template,
+			_ => return Err(anyhow::format_err!("{} does not take an instance", self.unit)),
+		};
+		if instance.is_empty() {
+			return Err(anyhow::format_err!("empty instance for {}", self.unit))
+		}
+		if let Some(pattern) = &self.instance_pattern {
+			if !pattern.is_match(instance) {
+				return Err(anyhow::format_err!(
+					"instance {:?} not allowed for {}",
+					instance,
+					self.unit
+				))
+			}
+		}
+		let unit = format!("{}@{}.{}", prefix, escape_instance(instance), suffix);
+		match unit.len() {
+			len if len > 255 => Err(anyhow::format_err!(
+				"instance {:?} too long for {}",
+				instance,
+				self.unit
+			)),
+			_ => Ok(unit),
+		}
+	}
+
+	/// The `prefix` and `suffix` of a `prefix@instance.suffix` unit name
+	pub fn template(&self) -> Option<(&str, &str)> {
+		let (prefix, rest) = self.unit.split_once('@')?;
+		let (_instance, suffix) = rest.rsplit_once('.')?;
+		Some((prefix, suffix))
+	}
+
+	/// The instance string of a `prefix@instance.suffix` unit name
+	pub fn instance(&self) -> Option<&str> {
+		let (_prefix, rest) = self.unit.split_once('@')?;
+		rest.rsplit_once('.').map(|(instance, _suffix)| instance)
+	}
+
 	pub fn short_name(&self) -> &str {
-		self.unit.split('.').next().unwrap()
+		self
+			.unit
+			.rsplit_once('.')
+			.map(|(name, _suffix)| name)
+			.unwrap_or(&self.unit)
+	}
+
+	pub fn name(&self) -> Cow<'_, str> {
+		match &self.name {
+			Some(name) => self.expand_instance(name),
+			None => Cow::Borrowed(self.short_name()),
+		}
+	}
+
+	/// Substitute the `%i` specifier with this unit's instance string
+	pub fn expand_instance<'s>(&self, s: &'s str) -> Cow<'s, str> {
+		match self.instance() {
+			Some(instance) if s.contains("%i") => s.replace("%i", instance).into(),
+			_ => Cow::Borrowed(s),
+		}
 	}
 
-	pub fn name(&self) -> &str {
-		self.name.as_ref().map(String::as_str).unwrap_or(self.short_name())
+	pub fn manager_kind(&self, cli: &Args) -> ManagerKind {
+		self.manager.unwrap_or(cli.manager_kind())
 	}
 
-	pub fn unique_id(&self, cli: &Args) -> String {
+	pub fn unique_id(&self, cli: &Args, scope: &Scope) -> String {
 		let unit = self.unit.replace(".", "_").replace("@", "_");
-		format!("{}_{}", cli.hass_device_id(), unit)
+		format!("{}_{}", cli.scope_device_id(scope), unit)
 	}
 
-	pub fn default_object_id(&self, cli: &Args) -> String {
-		format!("{}_{}", cli.hostname(), self.short_name())
+	pub fn default_object_id(&self, cli: &Args, scope: &Scope) -> String {
+		match scope.id_suffix() {
+			Some(suffix) => format!("{}_{}_{}", cli.hostname(), suffix, self.short_name()),
+			None => format!("{}_{}", cli.hostname(), self.short_name()),
+		}
 	}
 
-	pub fn object_id(&self, cli: &Args) -> Cow<str> {
+	pub fn object_id(&self, cli: &Args, scope: &Scope) -> Cow<'_, str> {
 		self
 			.object_id
 			.as_ref()
-			.map(|id| Cow::Borrowed(&id[..]))
-			.unwrap_or_else(|| self.default_object_id(cli).into())
+			.map(|id| self.expand_instance(id))
+			.unwrap_or_else(|| self.default_object_id(cli, scope).into())
+	}
+
+	pub fn mqtt_pub_topic(&self, cli: &Args, scope: &Scope) -> String {
+		format!("{}/{}/status", cli.scope_topic_root(scope), self.unit)
+	}
+
+	pub fn mqtt_sub_topic(&self, cli: &Args, scope: &Scope) -> String {
+		format!("{}/{}/activate", cli.scope_topic_root(scope), self.unit)
+	}
+
+	pub fn mqtt_result_topic(&self, cli: &Args, scope: &Scope) -> String {
+		format!("{}/{}/result", cli.scope_topic_root(scope), self.unit)
 	}
 
-	pub fn mqtt_pub_topic(&self, cli: &Args) -> String {
-		format!("{}/{}/status", cli.topic_root(), self.unit)
+	pub fn job_mode(&self) -> JobMode {
+		self.job_mode.unwrap_or(JobMode::Fail)
 	}
 
-	pub fn mqtt_sub_topic(&self, cli: &Args) -> String {
-		format!("{}/{}/activate", cli.topic_root(), self.unit)
+	/// Whether a command may enqueue its job with the given mode
+	pub fn allows_job_mode(&self, mode: JobMode) -> bool {
+		mode == self.job_mode() || self.job_modes.contains(&mode)
 	}
 
 	pub fn hass_platform(&self) -> &'static str {
@@ -189,45 +777,56 @@ impl UnitConfig {
 #[derive(Debug)]
 pub struct Unit<'a> {
 	pub cli: &'a Args,
-	pub unit: &'a UnitConfig,
-	pub(crate) config: once_cell::unsync::OnceCell<Box<dyn crate::payload::Entity + 'a>>,
+	pub scope: Scope,
+	pub unit: Cow<'a, UnitConfig>,
+	pub(crate) config: once_cell::unsync::OnceCell<Vec<Box<dyn crate::payload::Entity + 'a>>>,
 }
 
 impl<'a> Unit<'a> {
-	pub fn new(cli: &'a Args, unit: &'a UnitConfig) -> Self {
+	pub fn new(cli: &'a Args, scope: Scope, unit: Cow<'a, UnitConfig>) -> Self {
 		Self {
 			cli,
+			scope,
 			unit,
 			config: Default::default(),
 		}
 	}
 
-	pub fn unit_name(&self) -> &'a String {
+	pub fn unit_name(&self) -> &String {
 		&self.unit.unit
 	}
 
+	/// Identifies this unit among those of every scope
+	pub fn key(&self) -> String {
+		self.scope.key(self.unit_name())
+	}
+
 	pub fn unique_id(&self) -> String {
-		self.unit.unique_id(self.cli)
+		self.unit.unique_id(self.cli, &self.scope)
 	}
 
-	pub fn object_id(&self) -> Cow<'a, str> {
-		self.unit.object_id(self.cli)
+	pub fn object_id(&self) -> Cow<'_, str> {
+		self.unit.object_id(self.cli, &self.scope)
 	}
 
-	pub fn name(&self) -> &'a str {
+	pub fn name(&self) -> Cow<'_, str> {
 		self.unit.name()
 	}
 
-	pub fn icon(&self) -> Option<&'a String> {
+	pub fn icon(&self) -> Option<&String> {
 		self.unit.icon.as_ref()
 	}
 
 	pub fn mqtt_pub_topic(&self) -> String {
-		self.unit.mqtt_pub_topic(self.cli)
+		self.unit.mqtt_pub_topic(self.cli, &self.scope)
 	}
 
 	pub fn mqtt_sub_topic(&self) -> String {
-		self.unit.mqtt_sub_topic(self.cli)
+		self.unit.mqtt_sub_topic(self.cli, &self.scope)
+	}
+
+	pub fn mqtt_result_topic(&self) -> String {
+		self.unit.mqtt_result_topic(self.cli, &self.scope)
 	}
 }
 
@@ -235,7 +834,7 @@ impl<'a> Deref for Unit<'a> {
 	type Target = UnitConfig;
 
 	fn deref(&self) -> &Self::Target {
-		self.unit
+		&self.unit
 	}
 }
 
@@ -276,6 +875,17 @@ impl Default for UnitConfig {
 			icon: Default::default(),
 			name: Default::default(),
 			object_id: Default::default(),
+			instances: Default::default(),
+			instance_pattern: Default::default(),
+			manager: Default::default(),
+			job_mode: Default::default(),
+			job_modes: Default::default(),
+			enable: Default::default(),
+			mask: Default::default(),
+			pause: Default::default(),
+			clean: Default::default(),
+			properties: Default::default(),
+			persist_properties: Default::default(),
 			device_class: Default::default(),
 			read_only: Default::default(),
 			invert_state: Default::default(),
@@ -289,9 +899,144 @@ fn default_true() -> bool {
 	true
 }
 
+/// `fnmatch(3)`-style matching of `*`, `?` and `[...]`, as used by systemd unit patterns
+fn glob_match(pattern: &[u8], name: &[u8]) -> bool {
+	match (pattern.split_first(), name.split_first()) {
+		(None, None) => true,
+		(Some((b'*', rest)), _) => (0..=name.len()).any(|i| glob_match(rest, &name[i..])),
+		(Some((b'?', rest)), Some((_, name))) => glob_match(rest, name),
+		(Some((b'[', rest)), Some((&c, name))) => {
+			let (negate, start) = match rest.first() {
+				Some(b'!' | b'^') => (true, 1),
+				_ => (false, 0),
+			};
+			// a `]` right at the start is part of the class
+			let end = match rest.iter().skip(start + 1).position(|&b| b == b']') {
+				Some(end) => start + end + 1,
+				None => return c == b'[' && glob_match(rest, name),
+			};
+			let (class, rest) = (&rest[start..end], &rest[end + 1..]);
+			let mut found = false;
+			let mut i = 0;
+			while i < class.len() {
+				if i + 2 < class.len() && class[i + 1] == b'-' {
+					found |= (class[i]..=class[i + 2]).contains(&c);
+					i += 3;
+				} else {
+					found |= class[i] == c;
+					i += 1;
+				}
+			}
+			found != negate && glob_match(rest, name)
+		},
+		(Some((&p, rest)), Some((&c, name))) => p == c && glob_match(rest, name),
+		_ => false,
+	}
+}
+
+/// Escape a string for use as a unit instance, as `systemd-escape` does
+fn escape_instance(s: &str) -> String {
+	let mut escaped = String::with_capacity(s.len());
+	for (i, &b) in s.as_bytes().iter().enumerate() {
+		match b {
+			b'/' => escaped.push('-'),
+			b'.' if i == 0 => escaped.push_str("\\x2e"),
+			b'a'..=b'z' | b'A'..=b'Z' | b'0'..=b'9' | b':' | b'_' | b'.' => escaped.push(b as char),
+			b => escaped.push_str(&format!("\\x{:02x}", b)),
+		}
+	}
+	escaped
+}
+
 fn opt_str(s: &str) -> Option<&str> {
 	match s.is_empty() {
 		false => Some(s),
 		true => None,
 	}
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn matches(pattern: &str, name: &str) -> bool {
+		glob_match(pattern.as_bytes(), name.as_bytes())
+	}
+
+	#[test]
+	fn glob_wildcards() {
+		assert!(matches("*.service", "nginx.service"));
+		assert!(matches("*", ""));
+		assert!(!matches("*.service", "nginx.socket"));
+		assert!(matches("tty?.device", "tty1.device"));
+		assert!(!matches("tty?.device", "tty.device"));
+		assert!(!matches("tty?.device", "tty10.device"));
+	}
+
+	#[test]
+	fn glob_classes() {
+		assert!(matches("getty@tty[1-3].service", "getty@tty2.service"));
+		assert!(!matches("getty@tty[1-3].service", "getty@tty4.service"));
+		assert!(matches("[ab-]x", "-x"));
+		assert!(matches("[!a]*", "bar"));
+		assert!(!matches("[!a]*", "abc"));
+		assert!(matches("[^a]*", "bar"));
+		assert!(!matches("[^a]*", "abc"));
+	}
+
+	#[test]
+	fn glob_bracket_first_in_class() {
+		assert!(matches("[]a]x", "]x"));
+		assert!(matches("[]a]x", "ax"));
+		assert!(!matches("[]a]x", "bx"));
+		assert!(matches("[!]a]x", "bx"));
+		assert!(!matches("[!]a]x", "]x"));
+	}
+
+	#[test]
+	fn glob_unterminated_class() {
+		assert!(matches("foo[bar", "foo[bar"));
+		assert!(!matches("foo[bar", "foobar"));
+	}
+
+	#[test]
+	fn glob_escaped_names() {
+		let name = r"dev-disk-by\x2dlabel-data.device";
+		assert!(matches(r"dev-disk-by\x2dlabel-*.device", name));
+		assert!(!matches("dev-disk-by-label-*.device", name));
+		assert!(matches(r"*\x2d*", name));
+	}
+
+	#[test]
+	fn escape_instance_like_systemd_escape() {
+		assert_eq!(escape_instance("nas"), "nas");
+		assert_eq!(escape_instance("srv/backup.d"), "srv-backup.d");
+		assert_eq!(escape_instance(".hidden"), "\\x2ehidden");
+		assert_eq!(escape_instance("a-b c"), "a\\x2db\\x20c");
+	}
+
+	#[test]
+	fn payload_instance() {
+		let unit: UnitConfig = "backup@.service?instances=payload&instance-pattern=[a-z./-]*"
+			.parse()
+			.unwrap();
+		assert_eq!(unit.payload_instance("nas").unwrap(), "backup@nas.service");
+		assert_eq!(unit.payload_instance("srv/nas").unwrap(), "backup@srv-nas.service");
+		assert!(unit.payload_instance("").is_err());
+		assert!(unit.payload_instance("NAS").is_err());
+		assert!(unit.payload_instance(&"a".repeat(255)).is_err());
+	}
+
+	#[test]
+	fn payload_instance_needs_payload_template() {
+		let unit: UnitConfig = "backup@nas.service?instances=payload".parse().unwrap();
+		assert!(unit.payload_instance("nas").is_err());
+	}
+
+	#[test]
+	fn instance_pattern_is_checked_on_parse() {
+		assert!("backup@.service?instances=payload&instance-pattern=("
+			.parse::<UnitConfig>()
+			.is_err());
+	}
+}
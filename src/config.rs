@@ -0,0 +1,336 @@
+use {
+	crate::cli::{Args, ManagerKind, UnitConfig, UnitSelection},
+	anyhow::{format_err, Context, Result},
+	clap::{parser::ValueSource, ArgMatches, CommandFactory, FromArgMatches},
+	regex::Regex,
+	serde::{de::IntoDeserializer, Deserialize, Deserializer},
+	std::{
+		collections::BTreeMap,
+		fs,
+		path::{Path, PathBuf},
+	},
+	url::Url,
+};
+
+/// Settings read from `--config`, mirroring the command line options
+#[derive(Debug, Default, Deserialize)]
+#[serde(rename_all = "kebab-case", default, deny_unknown_fields)]
+pub struct Config {
+	pub mqtt_url: Option<Url>,
+	#[serde(deserialize_with = "deserialize_units")]
+	pub units: Vec<UnitConfig>,
+	#[serde(deserialize_with = "deserialize_units")]
+	pub unit_patterns: Vec<UnitConfig>,
+	pub hostname: Option<String>,
+	pub user: Option<bool>,
+	pub users: Option<bool>,
+	pub machines: Option<bool>,
+	pub power: Option<bool>,
+	pub isolate_targets: Option<Vec<String>>,
+	pub client_id: Option<String>,
+	pub clean_up: Option<bool>,
+	pub discovery_prefix: Option<String>,
+	pub mqtt_username: Option<String>,
+	pub mqtt_password: Option<String>,
+	pub mqtt5: Option<bool>,
+	pub mqtt_config: Option<bool>,
+	pub mqtt_track: Option<bool>,
+	pub state_directory: Option<PathBuf>,
+	/// commands that may be started through [crate::payload::ServiceCommand::Run], by name
+	pub run: BTreeMap<String, RunTemplate>,
+}
+
+/// A command that may be started as a transient unit on request
+///
+/// Requests can only fill in the declared parameters, never supply commands of their own.
+#[derive(Debug, Clone, Deserialize)]
+#[serde(rename_all = "kebab-case", deny_unknown_fields)]
+pub struct RunTemplate {
+	/// the command line, where `{param}` is replaced with the request's value for it
+	pub command: Vec<String>,
+	/// parameters that requests may provide, along with a regex their values must match in full
+	#[serde(default, deserialize_with = "deserialize_params")]
+	pub params: BTreeMap<String, Regex>,
+	#[serde(default)]
+	pub description: Option<String>,
+	/// run the command as another user of the system manager
+	#[serde(default)]
+	pub user: Option<String>,
+	#[serde(default)]
+	pub manager: Option<ManagerKind>,
+}
+
+impl RunTemplate {
+	/// The command line with the given parameters substituted, after checking them against their
+	/// patterns
+	pub fn command_line(&self, params: &BTreeMap<String, String>) -> Result<Vec<String>> {
+		for (name, value) in params {
+			match self.params.get(name) {
+				Some(pattern) if pattern.is_match(value) => (),
+				Some(..) => return Err(format_err!("invalid value for {}: {:?}", name, value)),
+				None => return Err(format_err!("unknown parameter {}", name)),
+			}
+		}
+		let command = self
+			.command
+			.iter()
+			.map(|arg| self.substitute(arg, params))
+			.collect::<Result<Vec<_>>>()?;
+		match command.first() {
+			Some(..) => Ok(command),
+			None => Err(format_err!("empty command")),
+		}
+	}
+
+	fn substitute(&self, arg: &str, params: &BTreeMap<String, String>) -> Result<String> {
+		let mut res = String::new();
+		let mut rest = arg;
+		while let Some(start) = rest.find('{') {
+			res.push_str(&rest[..start]);
+			let after = &rest[start + 1..];
+			match after.find('}').map(|end| &after[..end]) {
+				Some(name) if self.params.contains_key(name) => {
+					let value = params
+						.get(name)
+						.ok_or_else(|| format_err!("missing parameter {}", name))?;
+					res.push_str(value);
+					rest = &after[name.len() + 1..];
+				},
+				// not one of ours, leave it alone
+				_ => {
+					res.push('{');
+					rest = after;
+				},
+			}
+		}
+		res.push_str(rest);
+		Ok(res)
+	}
+}
+
+impl Config {
+	/// Parse a configuration file, picking the format by its extension
+	pub fn read(path: &Path) -> Result<Self> {
+		let data = fs::read_to_string(path).with_context(|| format!("failed to read {}", path.display()))?;
+		let config = match path.extension().and_then(|ext| ext.to_str()) {
+			Some("json") => serde_json::from_str(&data).map_err(Into::into),
+			Some("yaml" | "yml") => serde_yaml::from_str(&data).map_err(Into::into),
+			Some("toml") | None => toml::from_str(&data).map_err(Into::into),
+			Some(ext) => Err(format_err!("unsupported configuration format {}", ext)),
+		};
+		config.with_context(|| format!("failed to parse {}", path.display()))
+	}
+}
+
+/// Units may be listed either as `unit?setting=value` strings or as tables,
+/// or given as a table keyed by unit name
+pub(crate) fn deserialize_units<'de, D: Deserializer<'de>>(deserializer: D) -> Result<Vec<UnitConfig>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Entry {
+		Spec(String),
+		Config(UnitConfig),
+	}
+
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum Units {
+		List(Vec<Entry>),
+		Map(BTreeMap<String, UnitConfig>),
+	}
+
+	let units = match Units::deserialize(deserializer)? {
+		Units::List(units) => units
+			.into_iter()
+			.map(|unit| match unit {
+				Entry::Spec(spec) => spec.parse().map_err(serde::de::Error::custom),
+				Entry::Config(unit) => Ok(unit),
+			})
+			.collect::<Result<Vec<_>, _>>()?,
+		Units::Map(units) => units.into_iter().map(|(name, unit)| unit.with_unit(name)).collect(),
+	};
+	match units.iter().find(|unit| unit.unit.is_empty()) {
+		Some(..) => Err(serde::de::Error::custom("unit name missing")),
+		None => Ok(units),
+	}
+}
+
+fn deserialize_params<'de, D: Deserializer<'de>>(deserializer: D) -> Result<BTreeMap<String, Regex>, D::Error> {
+	BTreeMap::<String, String>::deserialize(deserializer)?
+		.into_iter()
+		.map(|(name, pattern)| {
+			Regex::new(&format!("^(?:{})$", pattern))
+				.map(|pattern| (name, pattern))
+				.map_err(serde::de::Error::custom)
+		})
+		.collect()
+}
+
+/// Accepts either a list or a comma-separated string, as query strings can't express lists
+pub(crate) fn deserialize_list<'de, D: Deserializer<'de>, T: Deserialize<'de>>(
+	deserializer: D,
+) -> Result<Vec<T>, D::Error> {
+	#[derive(Deserialize)]
+	#[serde(untagged)]
+	enum List<T> {
+		List(Vec<T>),
+		Str(String),
+	}
+
+	match List::deserialize(deserializer)? {
+		List::List(list) => Ok(list),
+		List::Str(s) => s
+			.split(',')
+			.map(str::trim)
+			.filter(|s| !s.is_empty())
+			.map(|s| T::deserialize(s.to_owned().into_deserializer()))
+			.collect(),
+	}
+}
+
+impl Args {
+	/// Parse the command line, along with any configuration file it refers to
+	pub fn load() -> Result<Self> {
+		let matches = Self::command().get_matches();
+		Self::from_matches(&matches).map_err(|e| match e.downcast::<clap::Error>() {
+			Ok(e) => e.exit(),
+			Err(e) => e,
+		})
+	}
+
+	fn from_matches(matches: &ArgMatches) -> Result<Self> {
+		let mut args = Self::from_arg_matches(matches)?;
+		if let Some(path) = &args.config {
+			let config = Config::read(path)?;
+			args.merge_config(config, matches);
+			args.check_conflicts()?;
+		}
+
+		Ok(args)
+	}
+
+	/// Settings from the file bypass the checks clap makes of the command line
+	fn check_conflicts(&self) -> Result<()> {
+		let conflicts = [("users", self.users), ("machines", self.machines)];
+		match conflicts.iter().find(|&&(_, set)| set && self.user) {
+			Some((name, _)) => Err(format_err!("`user` cannot be used with `{}`", name)),
+			None => Ok(()),
+		}
+	}
+
+	/// Read the units of interest again, picking up any changes to the configuration file
+	///
+	/// Other settings only take effect after a restart.
+	pub fn reload_selection(&self) -> Result<UnitSelection> {
+		let matches = Self::command().try_get_matches()?;
+		Self::from_matches(&matches).map(|args| args.selection)
+	}
+
+	/// Fill in anything not explicitly given on the command line or environment
+	pub fn merge_config(&mut self, config: Config, matches: &ArgMatches) {
+		let explicit = |id: &str| {
+			matches!(
+				matches.value_source(id),
+				Some(ValueSource::CommandLine | ValueSource::EnvVariable)
+			)
+		};
+		fn merge<T>(explicit: bool, value: &mut T, config: Option<T>) {
+			match config {
+				Some(config) if !explicit => *value = config,
+				_ => (),
+			}
+		}
+
+		merge(explicit("mqtt_url"), &mut self.mqtt_url, config.mqtt_url.map(Some));
+		merge(explicit("hostname"), &mut self.hostname, config.hostname.map(Some));
+		merge(explicit("user"), &mut self.user, config.user);
+		merge(explicit("users"), &mut self.users, config.users);
+		merge(explicit("machines"), &mut self.machines, config.machines);
+		merge(explicit("power"), &mut self.power, config.power);
+		merge(
+			explicit("isolate_targets"),
+			&mut self.isolate_targets,
+			config.isolate_targets,
+		);
+		merge(explicit("client_id"), &mut self.client_id, config.client_id.map(Some));
+		merge(explicit("clean_up"), &mut self.clean_up, config.clean_up);
+		merge(
+			explicit("discovery_prefix"),
+			&mut self.discovery_prefix,
+			config.discovery_prefix,
+		);
+		merge(
+			explicit("mqtt_username"),
+			&mut self.mqtt_username,
+			config.mqtt_username.map(Some),
+		);
+		merge(
+			explicit("mqtt_password"),
+			&mut self.mqtt_password,
+			config.mqtt_password.map(Some),
+		);
+		merge(explicit("mqtt5"), &mut self.mqtt5, config.mqtt5);
+		merge(explicit("mqtt_config"), &mut self.mqtt_config, config.mqtt_config);
+		merge(explicit("mqtt_track"), &mut self.mqtt_track, config.mqtt_track);
+		merge(
+			explicit("state_directory"),
+			&mut self.state_directory,
+			config.state_directory.map(Some),
+		);
+
+		self.run_templates = config.run;
+
+		// units given on the command line take precedence over the file's:
+		// later units replace earlier ones of the same name, while the first matching pattern wins
+		self.selection.units.splice(0..0, config.units);
+		self.selection.unit_patterns.extend(config.unit_patterns);
+	}
+}
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	fn template() -> RunTemplate {
+		toml::from_str(
+			r#"
+			command = ["/usr/bin/backup", "--host={host}", "{dest}", "{literal}"]
+			params = { host = "[a-z]+", dest = "/srv/[a-z]+" }
+			"#,
+		)
+		.unwrap()
+	}
+
+	fn params(params: &[(&str, &str)]) -> BTreeMap<String, String> {
+		params.iter().map(|&(k, v)| (k.into(), v.into())).collect()
+	}
+
+	#[test]
+	fn command_line_substitutes_params() {
+		let command = template()
+			.command_line(&params(&[("host", "nas"), ("dest", "/srv/backup")]))
+			.unwrap();
+		assert_eq!(command, ["/usr/bin/backup", "--host=nas", "/srv/backup", "{literal}"]);
+	}
+
+	#[test]
+	fn command_line_rejects_unknown_params() {
+		let res = template().command_line(&params(&[("host", "nas"), ("dest", "/srv/backup"), ("extra", "x")]));
+		assert!(res.is_err());
+	}
+
+	#[test]
+	fn command_line_rejects_partial_matches() {
+		assert!(template()
+			.command_line(&params(&[("host", "nas; rm -rf /"), ("dest", "/srv/backup")]))
+			.is_err());
+		assert!(template()
+			.command_line(&params(&[("host", "nas"), ("dest", "/etc/srv/backup")]))
+			.is_err());
+	}
+
+	#[test]
+	fn command_line_requires_used_params() {
+		assert!(template().command_line(&params(&[("host", "nas")])).is_err());
+	}
+}
@@ -1,55 +1,336 @@
 use {
 	crate::{
-		cli::{Args, Unit},
-		payload::{ServiceCommand, ServiceStatus, UnitCommand, UnitStatus},
+		cli::{Args, EnableEntity, JobMode, Unit, UnitConfig, UnitSelection},
+		manager::{
+			Event, JobResult, Logind, Machine, Machined, Manager, Power, PowerAction, Scope, UnitWatch, UserManager,
+		},
+		payload::{
+			ErrorKind, RunStatus, ServiceCommand, ServiceStatus, TargetStatus, UnitCommand, UnitError, UnitRequest,
+			UnitResult, UnitStatus,
+		},
+		state::State,
 	},
-	anyhow::Result,
-	futures::TryFutureExt,
-	log::{error, warn},
+	anyhow::{format_err, Result},
+	futures::{channel::mpsc, FutureExt, TryFutureExt},
+	log::{error, info, warn},
 	paho_mqtt::{self as mqtt, Message, QOS_0 as QOS},
-	std::{borrow::Cow, collections::HashMap, time::Duration},
+	std::{
+		borrow::Cow,
+		collections::{BTreeMap, BTreeSet, HashMap},
+		mem,
+		time::{Duration, SystemTime},
+	},
 	zbus_systemd::{
-		systemd1::{ManagerProxy, UnitProxy},
 		zbus,
+		zvariant::{self, OwnedObjectPath, Value},
 	},
 };
 
+/// A job enqueued through a [UnitCommand], awaiting its `JobRemoved` signal
+#[derive(Debug)]
+pub struct PendingJob {
+	pub scope: Scope,
+	pub job: String,
+	pub command: UnitCommand,
+	pub result_topic: String,
+	pub reply: Option<Reply>,
+}
+
+/// A transient unit started through [ServiceCommand::Run], followed until it exits
+#[derive(Debug)]
+pub struct RunningUnit {
+	pub template: String,
+	pub scope: Scope,
+	pub name: String,
+	pub watch: UnitWatch,
+	pub reply: Option<Reply>,
+}
+
+/// Where to send the outcome of an MQTT v5 request
+#[derive(Debug, Clone)]
+pub struct Reply {
+	pub topic: String,
+	pub correlation_data: Option<Vec<u8>>,
+}
+
+impl Reply {
+	/// The `ResponseTopic` and `CorrelationData` a command was sent with, if any
+	pub fn from_message(message: &Message) -> Option<Self> {
+		let props = message.properties();
+		props.get_string(mqtt::PropertyCode::ResponseTopic).map(|topic| Self {
+			topic,
+			correlation_data: props.get_binary(mqtt::PropertyCode::CorrelationData),
+		})
+	}
+
+	pub fn message<V: Into<Vec<u8>>>(&self, payload: V) -> Result<Message> {
+		let mut props = mqtt::Properties::new();
+		if let Some(data) = &self.correlation_data {
+			props.push_binary(mqtt::PropertyCode::CorrelationData, data.clone())?;
+		}
+		Ok(
+			mqtt::MessageBuilder::new()
+				.topic(&self.topic)
+				.payload(payload)
+				.qos(QOS)
+				.properties(props)
+				.finalize(),
+		)
+	}
+}
+
 pub struct Core<'c> {
 	pub cli: &'c Args,
-	pub units: HashMap<&'c str, Unit<'c>>,
+	/// Units from the command line and configuration file
+	pub configured: UnitSelection,
+	/// Units from the retained `config` topic
+	pub remote: UnitSelection,
+	/// Every unit of interest, including those tracked at runtime
+	pub selection: UnitSelection,
+	pub state: State,
+	pub units: HashMap<String, Unit<'c>>,
+	pub watches: HashMap<String, UnitWatch>,
+	pub managers: HashMap<Scope, Manager>,
+	pub logind: Option<Logind>,
+	/// Scopes of the logind users currently being followed, by UID
+	pub users: HashMap<u32, Scope>,
+	pub machined: Option<Machined>,
+	pub power: Option<Power>,
+	/// What logind allowed when we connected, which is all that gets exposed
+	pub power_actions: Vec<PowerAction>,
+	/// Jobs enqueued on request, by scope and job object path
+	pub jobs: HashMap<(Scope, String), PendingJob>,
+	/// The `isolate-targets`, by key
+	pub targets: HashMap<String, UnitWatch>,
+	/// Transient units started on request, by key
+	pub runs: HashMap<String, RunningUnit>,
+	/// Units found to need a daemon reload when last checked
+	pub need_daemon_reload: BTreeSet<String>,
 	pub mqtt: mqtt::AsyncClient,
-	pub sys: zbus::Connection,
+	events: mpsc::UnboundedSender<Event>,
 }
 
 impl<'c> Core<'c> {
-	pub async fn new(cli: &'c Args) -> Result<Core<'c>> {
+	pub async fn new(cli: &'c Args, events: mpsc::UnboundedSender<Event>) -> Result<Core<'c>> {
+		let state = State::load(cli)?;
 		Ok(Core {
-			sys: zbus::Connection::system().await?,
 			mqtt: mqtt::AsyncClient::new(cli.mqtt_create().finalize())?,
-			units: cli.units(),
+			configured: cli.selection.clone(),
+			remote: Default::default(),
+			selection: cli.selection.with_units(state.tracked(cli)),
+			state,
+			units: Default::default(),
+			watches: Default::default(),
+			managers: Default::default(),
+			logind: None,
+			users: Default::default(),
+			machined: None,
+			power: None,
+			power_actions: Default::default(),
+			jobs: Default::default(),
+			targets: Default::default(),
+			runs: Default::default(),
+			need_daemon_reload: Default::default(),
+			events,
 			cli,
 		})
 	}
 
-	pub async fn sys_manager(&self) -> Result<ManagerProxy> {
-		ManagerProxy::new(&self.sys).await.map_err(Into::into)
+	async fn scope_connection(&self, scope: &Scope) -> Result<zbus::Connection> {
+		match scope {
+			Scope::System => zbus::Connection::system().await,
+			Scope::User(..) => zbus::Connection::session().await,
+			Scope::Machine(..) => return Err(format_err!("{} must be reached through machined", scope)),
+		}
+		.map_err(Into::into)
+	}
+
+	/// Connect to every manager that configured units belong to
+	pub async fn connect_managers(&mut self) -> Result<()> {
+		for scope in self.selection.scopes(self.cli) {
+			if self.managers.contains_key(&scope) {
+				continue
+			}
+			let res = async {
+				let conn = self.scope_connection(&scope).await?;
+				self.add_manager(scope.clone(), conn).await
+			}
+			.await;
+			match res {
+				Ok(()) => (),
+				Err(e) if scope == self.cli.scope() => return Err(e),
+				Err(e) => error!("Failed to connect to {} manager: {:?}", scope, e),
+			}
+		}
+
+		if self.cli.users && self.logind.is_none() {
+			let logind = Logind::new(self.events.clone()).await?;
+			let users = logind.users().await?;
+			self.logind = Some(logind);
+			for user in users {
+				let scope = user.scope();
+				match self.connect_user(&user).await {
+					Ok(()) => info!("following {} manager", scope),
+					Err(e) => error!("Failed to connect to {} manager: {:?}", scope, e),
+				}
+			}
+		}
+
+		if self.cli.machines && self.machined.is_none() {
+			let machined = Machined::new(self.events.clone()).await?;
+			let machines = machined.machines().await?;
+			self.machined = Some(machined);
+			for machine in machines {
+				let scope = machine.scope();
+				let res = async {
+					let conn = machine.connect().await?;
+					self.add_manager(scope.clone(), conn).await
+				}
+				.await;
+				match res {
+					Ok(()) => info!("following {} manager", scope),
+					Err(e) => error!("Failed to connect to {} manager: {:?}", scope, e),
+				}
+			}
+		}
+
+		if !self.cli.isolate_targets.is_empty() && self.targets.is_empty() {
+			let scope = self.cli.scope();
+			let manager = self
+				.managers
+				.get(&scope)
+				.ok_or_else(|| format_err!("not connected to {} manager", scope))?;
+			for target in &self.cli.isolate_targets {
+				let key = scope.key(target);
+				let watch = manager.watch_unit(target, key.clone(), self.events.clone()).await?;
+				self.targets.insert(key, watch);
+			}
+		}
+
+		if self.cli.power && self.power.is_none() {
+			let power = Power::new().await?;
+			self.power_actions = power.available().await?;
+			self.power = Some(power);
+		}
+
+		Ok(())
+	}
+
+	/// Start managing a scope's configured units through the given connection
+	async fn add_manager(&mut self, scope: Scope, conn: zbus::Connection) -> Result<()> {
+		let manager = Manager::new(scope.clone(), conn, self.events.clone()).await?;
+		self.managers.insert(scope.clone(), manager);
+		for (key, unit) in self.selection.scope_units(self.cli, &scope) {
+			self.units.entry(key).or_insert(unit);
+		}
+		self.expand_scope_patterns(&scope).await
+	}
+
+	async fn connect_user(&mut self, user: &UserManager) -> Result<()> {
+		let scope = user.scope();
+		let conn = user.connect().await?;
+		self.add_manager(scope.clone(), conn).await?;
+		self.users.insert(user.uid, scope);
+		Ok(())
+	}
+
+	/// Resolve `--unit-pattern` globs and template units into concrete units
+	///
+	/// Explicitly listed units take precedence over any pattern that also matches them.
+	pub async fn expand_unit_patterns(&mut self) -> Result<()> {
+		let scopes: Vec<_> = self.managers.keys().cloned().collect();
+		for scope in scopes {
+			self.expand_scope_patterns(&scope).await?;
+		}
+
+		Ok(())
+	}
+
+	async fn expand_scope_patterns(&mut self, scope: &Scope) -> Result<()> {
+		let manager = match self.managers.get(scope) {
+			Some(manager) => manager,
+			None => return Ok(()),
+		};
+		let patterns = self.selection.unit_patterns(self.cli, scope.kind());
+		if patterns.is_empty() {
+			return Ok(())
+		}
+
+		let loaded = manager.proxy.list_units_by_patterns(Vec::new(), patterns).await?;
+		let files = match self.selection.unit_file_patterns(self.cli, scope.kind()) {
+			patterns if patterns.is_empty() => Vec::new(),
+			patterns => manager.proxy.list_unit_files_by_patterns(Vec::new(), patterns).await?,
+		};
+		let names = loaded
+			.into_iter()
+			.filter(|(_, _, load_state, ..)| load_state != "not-found")
+			.map(|(name, ..)| name)
+			.chain(
+				files
+					.into_iter()
+					.filter_map(|(path, _state)| path.rsplit('/').next().map(ToOwned::to_owned)),
+			);
+
+		for name in names {
+			if self.units.contains_key(&scope.key(&name)) || name.contains("@.") {
+				// already tracked, or an uninstantiated template
+				continue
+			}
+			if let Some(unit) = self.pattern_unit(scope, &name) {
+				self.units.insert(unit.key(), unit);
+			}
+		}
+
+		Ok(())
+	}
+
+	fn pattern_unit(&self, scope: &Scope, name: &str) -> Option<Unit<'c>> {
+		self
+			.selection
+			.unit_selectors(self.cli, scope.kind())
+			.find(|p| p.matches(name))
+			.map(|pattern| Unit::new(self.cli, scope.clone(), Cow::Owned(pattern.with_unit(name))))
 	}
 
 	pub fn mqtt_will(&self) -> Message {
 		let payload = ServiceStatus {
 			is_active: false,
 			units: Default::default(),
+			need_daemon_reload: Default::default(),
 		};
 		Message::new_retained(self.cli.mqtt_pub_topic(), payload.encode(), mqtt::QOS_1)
 	}
 
-	pub async fn announce(&self) -> Result<()> {
-		if self.cli.use_mqtt() {
-			let mut futures = Vec::new();
+	/// Every unit whose files changed since its manager loaded them
+	async fn stale_units(&self) -> BTreeSet<String> {
+		let checks = self.watches.iter().filter_map(|(key, watch)| {
+			let manager = self.units.get(key).and_then(|unit| self.managers.get(&unit.scope))?;
+			Some(manager.need_daemon_reload(&watch.proxy).map(move |res| (key, res)))
+		});
+		let mut stale = BTreeSet::new();
+		for (key, res) in futures::future::join_all(checks).await {
+			match res {
+				Ok(true) => {
+					stale.insert(key.clone());
+				},
+				Ok(false) => (),
+				Err(e) => warn!("Failed to check whether {} needs a daemon reload: {:?}", key, e),
+			}
+		}
+		stale
+	}
 
+	pub async fn publish_status(&self) -> Result<()> {
+		if self.cli.use_mqtt() {
 			let payload = ServiceStatus {
 				is_active: true,
-				units: self.units.keys().map(|&s| Cow::Borrowed(s)).collect(),
+				units: self.units.keys().map(|s| Cow::Borrowed(&s[..])).collect(),
+				need_daemon_reload: self
+					.need_daemon_reload
+					.iter()
+					.filter(|key| self.units.contains_key(&key[..]))
+					.map(|s| Cow::Borrowed(&s[..]))
+					.collect(),
 			};
 			self
 				.mqtt
@@ -59,31 +340,138 @@ impl<'c> Core<'c> {
 					mqtt::QOS_1,
 				))
 				.await?;
+		}
+
+		Ok(())
+	}
+
+	/// Look for units whose files changed on disk, which systemd doesn't signal,
+	/// and publish the host and units whose state differs from the last check
+	pub async fn check_daemon_reload(&mut self) -> Result<()> {
+		let stale = self.stale_units().await;
+		if stale == self.need_daemon_reload {
+			return Ok(())
+		}
+		let changed: Vec<_> = stale.symmetric_difference(&self.need_daemon_reload).cloned().collect();
+		self.need_daemon_reload = stale;
+		self.publish_status().await?;
+		futures::future::try_join_all(changed.iter().map(|key| self.inform_unit(key))).await?;
+
+		Ok(())
+	}
+
+	pub async fn announce(&self) -> Result<()> {
+		if self.cli.use_mqtt() {
+			let mut futures = Vec::new();
+
+			self.publish_status().await?;
 
 			for unit in self.units.values() {
-				futures.push(self.mqtt.publish(unit.hass_announce(true)?));
+				futures.extend(unit.hass_announce(true)?.into_iter().map(|msg| self.mqtt.publish(msg)));
 			}
 			let global = self.cli.hass_diag_button();
 			futures.push(self.mqtt.publish(self.cli.hass_announce(&global, true)?));
+			let errors = self.cli.hass_error_sensor();
+			futures.push(self.mqtt.publish(self.cli.hass_announce(&errors, true)?));
+			let reload = self.cli.hass_reload_button();
+			futures.push(self.mqtt.publish(self.cli.hass_announce(&reload, true)?));
+			let need_reload = self.cli.hass_reload_sensor();
+			futures.push(self.mqtt.publish(self.cli.hass_announce(&need_reload, true)?));
+			for &action in &self.power_actions {
+				let button = self.cli.hass_power_button(action);
+				futures.push(self.mqtt.publish(self.cli.hass_announce(&button, true)?));
+			}
+			if !self.cli.isolate_targets.is_empty() {
+				let select = self.cli.hass_isolate_select();
+				futures.push(self.mqtt.publish(self.cli.hass_announce(&select, true)?));
+			}
 
 			futures::future::try_join_all(futures).await?;
+			self.publish_target().await?;
 		}
 
 		Ok(())
 	}
 
-	pub async fn connect(&self, manager: &ManagerProxy<'_>) -> Result<()> {
-		manager.subscribe().await?;
+	/// Publish which of the `isolate-targets` is active
+	///
+	/// Targets pull each other in, so the one most recently reached wins.
+	pub async fn publish_target(&self) -> Result<()> {
+		if !self.cli.use_mqtt() || self.targets.is_empty() {
+			return Ok(())
+		}
+		let mut current = None;
+		for target in &self.cli.isolate_targets {
+			let proxy = match self.targets.get(&self.cli.scope().key(target)) {
+				Some(watch) => &watch.proxy,
+				None => continue,
+			};
+			if proxy.active_state().await? != "active" {
+				continue
+			}
+			let entered = proxy.active_enter_timestamp().await?;
+			match current {
+				Some((_, latest)) if latest >= entered => (),
+				_ => current = Some((target, entered)),
+			}
+		}
+		let payload = match current {
+			Some((target, _)) => TargetStatus { target },
+			// the select has no way to show none of its options, so keep whatever it had
+			None => return Ok(()),
+		};
+		self
+			.mqtt
+			.publish(Message::new_retained(
+				self.cli.mqtt_target_topic(),
+				payload.encode(),
+				QOS,
+			))
+			.await?;
+
+		Ok(())
+	}
+
+	/// Switch to one of the `isolate-targets`, as `systemctl isolate` would
+	pub async fn isolate(&self, target: &str) -> Result<()> {
+		if !self.cli.isolate_targets.iter().any(|t| t == target) {
+			return Err(format_err!("{} is not one of the isolate targets", target))
+		}
+		let scope = self.cli.scope();
+		let watch = self
+			.targets
+			.get(&scope.key(target))
+			.ok_or_else(|| format_err!("not following {}", target))?;
+		if !watch.proxy.allow_isolate().await? {
+			return Err(format_err!("{} does not allow isolation", target))
+		}
+		let manager = self
+			.managers
+			.get(&scope)
+			.ok_or_else(|| format_err!("not connected to {} manager", scope))?;
+		manager
+			.proxy
+			.start_unit(target.into(), JobMode::Isolate.to_string())
+			.await?;
+
+		Ok(())
+	}
 
+	pub async fn connect(&self) -> Result<()> {
 		if self.cli.use_mqtt() {
 			let mut opts = self.cli.mqtt_connect();
 			opts.will_message(self.mqtt_will());
 			self.mqtt.connect(opts.finalize()).await?;
-			self
-				.mqtt
-				.subscribe(format!("{}/+/activate", self.cli.topic_root()), QOS)
-				.await?;
+			for scope in self.managers.keys() {
+				self
+					.mqtt
+					.subscribe(format!("{}/+/activate", self.cli.scope_topic_root(scope)), QOS)
+					.await?;
+			}
 			self.mqtt.subscribe(self.cli.mqtt_sub_topic(), QOS).await?;
+			if self.cli.mqtt_config {
+				self.mqtt.subscribe(self.cli.mqtt_config_topic(), mqtt::QOS_1).await?;
+			}
 		}
 
 		Ok(())
@@ -92,21 +480,31 @@ impl<'c> Core<'c> {
 	pub async fn disconnect(&self) -> Result<()> {
 		if self.cli.use_mqtt() {
 			let global = self.cli.hass_diag_button();
+			let errors = self.cli.hass_error_sensor();
+			let reload = self.cli.hass_reload_button();
+			let need_reload = self.cli.hass_reload_sensor();
 			let mut futures = Vec::new();
 			if self.cli.clean_up {
 				for unit in self.units.values() {
-					futures.push(
-						self
-							.mqtt
-							.publish(Message::new_retained(unit.hass_config_topic(), "", QOS)),
-					);
+					futures.extend(unit.hass_retract().into_iter().map(|msg| self.mqtt.publish(msg)));
 				}
 			} else {
 				// unset retain flag on entity configs
 				for unit in self.units.values() {
-					futures.push(self.mqtt.publish(unit.hass_announce(false)?));
+					futures.extend(unit.hass_announce(false)?.into_iter().map(|msg| self.mqtt.publish(msg)));
 				}
 				futures.push(self.mqtt.publish(self.cli.hass_announce(&global, false)?));
+				futures.push(self.mqtt.publish(self.cli.hass_announce(&errors, false)?));
+				futures.push(self.mqtt.publish(self.cli.hass_announce(&reload, false)?));
+				futures.push(self.mqtt.publish(self.cli.hass_announce(&need_reload, false)?));
+				for &action in &self.power_actions {
+					let button = self.cli.hass_power_button(action);
+					futures.push(self.mqtt.publish(self.cli.hass_announce(&button, false)?));
+				}
+				if !self.cli.isolate_targets.is_empty() {
+					let select = self.cli.hass_isolate_select();
+					futures.push(self.mqtt.publish(self.cli.hass_announce(&select, false)?));
+				}
 			}
 			futures.push(self.mqtt.publish(self.mqtt_will()));
 
@@ -125,47 +523,606 @@ impl<'c> Core<'c> {
 		Ok(())
 	}
 
-	pub async fn unit_proxy(&self, manager: &ManagerProxy<'_>, unit: &Unit<'c>) -> Result<UnitProxy> {
-		Ok(
-			UnitProxy::builder(&self.sys)
-				.path(manager.load_unit(unit.unit_name().into()).await?)?
-				.build()
-				.await?,
-		)
+	async fn unit_watch(&self, unit: &Unit<'c>) -> Result<UnitWatch> {
+		let manager = self
+			.managers
+			.get(&unit.scope)
+			.ok_or_else(|| format_err!("not connected to {} manager", unit.scope))?;
+		manager
+			.watch_unit(unit.unit_name(), unit.key(), self.events.clone())
+			.await
 	}
 
-	pub async fn unit_proxies<'m, 's: 'm>(
-		&'s self,
-		manager: &ManagerProxy<'m>,
-	) -> HashMap<&'c str, (&'s Unit<'c>, UnitProxy<'m>)> {
-		let proxies = futures::future::join_all(
+	/// Set up proxies for every tracked unit and start following their state
+	pub async fn watch_units(&mut self) {
+		let watches = futures::future::join_all(
 			self
 				.units
 				.iter()
-				.map(|(&name, unit)| self.unit_proxy(manager, unit).map_ok(move |proxy| (name, proxy))),
-		);
+				.filter(|(key, unit)| !self.watches.contains_key(&key[..]) && !unit.unit.is_payload_template())
+				.map(|(key, unit)| self.unit_watch(unit).map_ok(move |watch| (key.clone(), watch))),
+		)
+		.await;
 
-		proxies
-			.await
-			.into_iter()
-			.filter_map(|r| match r {
-				Err(e) => {
-					error!("Failed to set up unit: {:?}", e);
-					None
+		for watch in watches {
+			match watch {
+				Err(e) => error!("Failed to set up unit: {:?}", e),
+				Ok((key, watch)) => {
+					self.watches.insert(key, watch);
 				},
-				Ok((n, p)) => self.units.get(n).map(|u| (n, (u, p))),
+			}
+		}
+	}
+
+	/// Start tracking a unit that appeared after startup, if any pattern is interested in it
+	pub async fn track_unit(&mut self, scope: &Scope, name: &str) -> Result<bool> {
+		let key = scope.key(name);
+		if self.units.contains_key(&key) || name.contains("@.") {
+			return Ok(false)
+		}
+		let unit = match self.pattern_unit(scope, name) {
+			Some(unit) => unit,
+			None => return Ok(false),
+		};
+
+		let watch = self.unit_watch(&unit).await?;
+		if watch.proxy.load_state().await? == "not-found" {
+			// merely referenced, such as by a dependency of another unit
+			return Ok(false)
+		}
+		if self.cli.use_mqtt() {
+			futures::future::try_join_all(unit.hass_announce(true)?.into_iter().map(|msg| self.mqtt.publish(msg))).await?;
+		}
+		self.units.insert(key.clone(), unit);
+		self.watches.insert(key.clone(), watch);
+		self.publish_status().await?;
+		self.inform_unit(&key).await?;
+
+		Ok(true)
+	}
+
+	/// Stop tracking a pattern-matched unit once systemd forgets about it
+	///
+	/// Units backed by a unit file are kept, since they only disappear from the manager when
+	/// garbage collected while inactive.
+	pub async fn untrack_unit(&mut self, scope: &Scope, name: &str) -> Result<bool> {
+		let key = scope.key(name);
+		if !self.units.contains_key(&key) || self.selection.is_explicit(self.cli, scope, name) {
+			return Ok(false)
+		}
+		if let Some(manager) = self.managers.get(scope) {
+			if !manager
+				.proxy
+				.list_unit_files_by_patterns(Vec::new(), vec![name.into()])
+				.await?
+				.is_empty()
+			{
+				return Ok(false)
+			}
+		}
+
+		self.watches.remove(&key);
+		let unit = match self.units.remove(&key) {
+			Some(unit) => unit,
+			None => return Ok(false),
+		};
+		self.publish_status().await?;
+		if self.cli.use_mqtt() && self.cli.clean_up {
+			futures::future::try_join_all(unit.hass_retract().into_iter().map(|msg| self.mqtt.publish(msg))).await?;
+		}
+
+		Ok(true)
+	}
+
+	/// Every unit of interest, layering the retained MQTT configuration and any units
+	/// tracked at runtime over those configured locally
+	pub fn effective_selection(&self) -> UnitSelection {
+		self
+			.configured
+			.with_selection(&self.remote)
+			.with_units(self.state.tracked(self.cli))
+	}
+
+	/// Switch to a new set of units without disconnecting, only announcing what changed
+	pub async fn reload(&mut self, configured: UnitSelection) -> Result<()> {
+		let previous = mem::replace(&mut self.configured, configured);
+		let res = self.apply_selection(self.effective_selection()).await;
+		if res.is_err() {
+			self.configured = previous;
+		}
+		res
+	}
+
+	/// Apply units published to the retained configuration topic
+	pub async fn set_remote(&mut self, remote: UnitSelection) -> Result<()> {
+		let previous = mem::replace(&mut self.remote, remote);
+		let res = self.apply_selection(self.effective_selection()).await;
+		if res.is_err() {
+			self.remote = previous;
+		}
+		res
+	}
+
+	/// Start exposing a unit on request, remembering it for next time
+	pub async fn track(&mut self, unit: UnitConfig) -> Result<()> {
+		let previous = self.state.clone();
+		self.state.track(unit);
+		self.apply_state(previous).await
+	}
+
+	pub async fn untrack(&mut self, unit: &str) -> Result<bool> {
+		let previous = self.state.clone();
+		if !self.state.untrack(unit) {
+			return Ok(false)
+		}
+		self.apply_state(previous).await.map(|()| true)
+	}
+
+	async fn apply_state(&mut self, previous: State) -> Result<()> {
+		if let Err(e) = self.apply_selection(self.effective_selection()).await {
+			self.state = previous;
+			return Err(e)
+		}
+		self.state.save(self.cli)
+	}
+
+	async fn apply_selection(&mut self, selection: UnitSelection) -> Result<()> {
+		let previous_selection = mem::replace(&mut self.selection, selection);
+		let previous = mem::take(&mut self.units);
+		let connected: Vec<_> = self.managers.keys().cloned().collect();
+		let res = async {
+			let scopes: Vec<_> = self.managers.keys().cloned().collect();
+			for scope in scopes {
+				for (key, unit) in self.selection.scope_units(self.cli, &scope) {
+					self.units.entry(key).or_insert(unit);
+				}
+				self.expand_scope_patterns(&scope).await?;
+			}
+			// pick up managers that newly configured units belong to
+			self.connect_managers().await
+		}
+		.await;
+		if let Err(e) = res {
+			self.selection = previous_selection;
+			self.units = previous;
+			return Err(e)
+		}
+
+		let units = &self.units;
+		self.watches.retain(|key, _| units.contains_key(key));
+		self.watch_units().await;
+
+		if self.cli.use_mqtt() {
+			let mut announced = HashMap::new();
+			for unit in previous.values() {
+				for msg in unit.hass_announce(true)? {
+					announced.insert(msg.topic().to_owned(), msg);
+				}
+			}
+			let mut futures = Vec::new();
+			for unit in self.units.values() {
+				for msg in unit.hass_announce(true)? {
+					match announced.remove(msg.topic()) {
+						Some(previous) if previous.payload() == msg.payload() => (),
+						_ => futures.push(self.mqtt.publish(msg)),
+					}
+				}
+			}
+			// whatever remains belongs to entities that no longer exist
+			futures.extend(
+				announced
+					.into_keys()
+					.map(|topic| self.mqtt.publish(Message::new_retained(topic, "", QOS))),
+			);
+			futures::future::try_join_all(futures).await?;
+			for scope in self.managers.keys().filter(|scope| !connected.contains(scope)) {
+				self
+					.mqtt
+					.subscribe(format!("{}/+/activate", self.cli.scope_topic_root(scope)), QOS)
+					.await?;
+			}
+		}
+		self.publish_status().await?;
+
+		let added: Vec<_> = self
+			.units
+			.keys()
+			.filter(|key| !previous.contains_key(&key[..]))
+			.cloned()
+			.collect();
+		futures::future::try_join_all(added.iter().map(|key| self.inform_unit(key))).await?;
+
+		Ok(())
+	}
+
+	/// Bring a newly logged-in user's units online
+	pub async fn add_user(&mut self, user: &UserManager) -> Result<()> {
+		let scope = user.scope();
+		if self.managers.contains_key(&scope) {
+			return Ok(())
+		}
+		self.connect_user(user).await?;
+		self.announce_scope(&scope).await
+	}
+
+	/// Bring a newly started container's units online
+	pub async fn add_machine(&mut self, machine: &Machine) -> Result<()> {
+		let scope = machine.scope();
+		if self.managers.contains_key(&scope) {
+			return Ok(())
+		}
+		let conn = machine.connect().await?;
+		self.add_manager(scope.clone(), conn).await?;
+		self.announce_scope(&scope).await
+	}
+
+	/// Start following and publishing the units of a manager that appeared after startup
+	async fn announce_scope(&mut self, scope: &Scope) -> Result<()> {
+		let scope = scope.clone();
+		self.watch_units().await;
+
+		if self.cli.use_mqtt() {
+			let mut futures = Vec::new();
+			for unit in self.units.values().filter(|unit| unit.scope == scope) {
+				futures.extend(unit.hass_announce(true)?.into_iter().map(|msg| self.mqtt.publish(msg)));
+			}
+			futures::future::try_join_all(futures).await?;
+			self
+				.mqtt
+				.subscribe(format!("{}/+/activate", self.cli.scope_topic_root(&scope)), QOS)
+				.await?;
+		}
+		self.publish_status().await?;
+		let keys: Vec<_> = self
+			.units
+			.iter()
+			.filter(|(_, unit)| unit.scope == scope)
+			.map(|(key, _)| key.clone())
+			.collect();
+		futures::future::try_join_all(keys.iter().map(|key| self.inform_unit(key))).await?;
+
+		Ok(())
+	}
+
+	/// Set up a scope's units again after its manager reloaded, as their proxies may be stale
+	async fn refresh_scope(&mut self, scope: &Scope) -> Result<()> {
+		let keys: Vec<_> = self
+			.units
+			.iter()
+			.filter(|(_, unit)| unit.scope == *scope)
+			.map(|(key, _)| key.clone())
+			.collect();
+		for key in &keys {
+			self.watches.remove(key);
+		}
+		self.watch_units().await;
+		// the reload picked up whatever changed on disk
+		self.need_daemon_reload = self.stale_units().await;
+		self.publish_status().await?;
+		futures::future::try_join_all(keys.iter().map(|key| self.inform_unit(key))).await?;
+
+		Ok(())
+	}
+
+	/// Forget a scope's manager along with all of its units
+	pub async fn remove_scope(&mut self, scope: &Scope) -> Result<()> {
+		self.users.retain(|_, s| s != scope);
+		self.jobs.retain(|(s, _), _| s != scope);
+		self.runs.retain(|_, run| run.scope != *scope);
+		self.managers.remove(scope);
+		let keys: Vec<_> = self
+			.units
+			.iter()
+			.filter(|(_, unit)| unit.scope == *scope)
+			.map(|(key, _)| key.clone())
+			.collect();
+		let mut units = Vec::new();
+		for key in keys {
+			self.watches.remove(&key);
+			units.extend(self.units.remove(&key));
+		}
+
+		if self.cli.use_mqtt() {
+			self.publish_status().await?;
+			self
+				.mqtt
+				.unsubscribe(format!("{}/+/activate", self.cli.scope_topic_root(scope)))
+				.await?;
+			if self.cli.clean_up {
+				futures::future::try_join_all(
+					units
+						.iter()
+						.flat_map(|unit| unit.hass_retract())
+						.map(|msg| self.mqtt.publish(msg)),
+				)
+				.await?;
+			}
+		}
+
+		Ok(())
+	}
+
+	pub async fn handle_event(&mut self, event: Event) -> Result<()> {
+		match event {
+			Event::UnitChanged(key) if self.runs.contains_key(&key) =>
+				if let Err(e) = self.check_run(&key).await {
+					error!("Failed to follow {}: {:?}", key, e);
+				},
+			Event::UnitChanged(key) => {
+				if self.targets.contains_key(&key) {
+					self.publish_target().await?;
+				}
+				self.update_unit(&key).await
+			},
+			Event::Reloaded(scope) =>
+				if let Err(e) = self.refresh_scope(&scope).await {
+					error!("Failed to refresh {} units: {:?}", scope, e);
+				},
+			Event::UnitFilesChanged => {
+				// such as by `systemctl enable`, which only shows in the unit file state
+				let keys: Vec<_> = self
+					.units
+					.iter()
+					.filter(|(_, unit)| unit.unit.enable.is_some())
+					.map(|(key, _)| key.clone())
+					.collect();
+				for key in &keys {
+					self.update_unit(key).await;
+				}
+				self.check_daemon_reload().await?
+			},
+			Event::JobNew(scope, unit) => self.update_unit(&scope.key(&unit)).await,
+			Event::JobRemoved(scope, unit, result) => {
+				if let Some(job) = self.jobs.remove(&(scope.clone(), result.job.clone())) {
+					self.publish_result(&job, &result).await?;
+				}
+				self.update_unit(&scope.key(&unit)).await
+			},
+			Event::UnitNew(scope, unit) => match self.track_unit(&scope, &unit).await {
+				Ok(true) => info!("tracking new unit {}", scope.key(&unit)),
+				Ok(false) => (),
+				Err(e) => error!("Failed to track new unit {}: {:?}", scope.key(&unit), e),
+			},
+			Event::UnitRemoved(scope, unit) => match self.untrack_unit(&scope, &unit).await {
+				Ok(true) => info!("no longer tracking removed unit {}", scope.key(&unit)),
+				Ok(false) => (),
+				Err(e) => error!("Failed to untrack removed unit {}: {:?}", scope.key(&unit), e),
+			},
+			Event::Lost(scope @ Scope::User(..)) if self.cli.users => {
+				info!("lost {} systemd connection", scope);
+				self.remove_scope(&scope).await?;
+			},
+			Event::Lost(scope @ Scope::Machine(..)) => {
+				info!("lost {} systemd connection", scope);
+				self.remove_scope(&scope).await?;
+			},
+			Event::Lost(scope) => return Err(format_err!("lost {} systemd connection", scope)),
+			Event::UserNew(user) => match self.add_user(&user).await {
+				Ok(()) => info!("following {} manager", user.scope()),
+				Err(e) => error!("Failed to follow {} manager: {:?}", user.scope(), e),
+			},
+			Event::UserRemoved(uid) =>
+				if let Some(scope) = self.users.get(&uid).cloned() {
+					info!("{} logged out", scope);
+					self.remove_scope(&scope).await?;
+				},
+			Event::MachineNew(machine) => match self.add_machine(&machine).await {
+				Ok(()) => info!("following {} manager", machine.scope()),
+				Err(e) => error!("Failed to follow {} manager: {:?}", machine.scope(), e),
+			},
+			Event::MachineRemoved(name) => {
+				let scope = Scope::Machine(name);
+				if self.managers.contains_key(&scope) {
+					info!("{} stopped", scope);
+					self.remove_scope(&scope).await?;
+				}
+			},
+		}
+
+		Ok(())
+	}
+
+	/// Let whoever asked for a job know how it went
+	pub async fn publish_result(&self, job: &PendingJob, result: &JobResult) -> Result<()> {
+		if result.result != "done" {
+			warn!("{:?} job {} {}", job.command, result.id, result.result);
+		}
+		if self.cli.use_mqtt() {
+			let payload = UnitResult {
+				event_type: &result.result,
+				command: &job.command,
+				job_id: Some(result.id),
+				result: &result.result,
+			}
+			.encode();
+			if let Some(reply) = &job.reply {
+				self.mqtt.publish(reply.message(&payload[..])?).await?;
+			}
+			self.mqtt.publish(Message::new(&job.result_topic, payload, QOS)).await?;
+		}
+
+		Ok(())
+	}
+
+	/// Start a transient unit from one of the configured run templates
+	pub async fn run(&mut self, template: &str, params: &BTreeMap<String, String>, reply: Option<Reply>) -> Result<()> {
+		let run = self
+			.cli
+			.run_templates
+			.get(template)
+			.ok_or_else(|| format_err!("no run template named {}", template))?;
+		let command = run.command_line(params)?;
+		let scope = self
+			.cli
+			.kind_scope(run.manager.unwrap_or_else(|| self.cli.manager_kind()));
+		let manager = self
+			.managers
+			.get(&scope)
+			.ok_or_else(|| format_err!("not connected to {} manager", scope))?;
+
+		let id = SystemTime::now()
+			.duration_since(SystemTime::UNIX_EPOCH)
+			.map(|t| t.as_micros())
+			.unwrap_or_default();
+		let template_name: String = template
+			.chars()
+			.map(|c| match c.is_ascii_alphanumeric() || c == '-' || c == '_' {
+				true => c,
+				false => '_',
 			})
-			.collect()
+			.collect();
+		let name = format!("{}-run-{}-{}.service", env!("CARGO_PKG_NAME"), template_name, id);
+
+		let description = run.description.clone().unwrap_or_else(|| command.join(" "));
+		let mut properties = vec![
+			("Description", Value::from(description)),
+			("ExecStart", Value::from(vec![(command[0].clone(), command, false)])),
+			// keep the unit around after it exits until its result has been read
+			("AddRef", Value::from(true)),
+			("CollectMode", Value::from("inactive-or-failed")),
+		];
+		if let Some(user) = &run.user {
+			properties.push(("User", Value::from(user.clone())));
+		}
+		manager.start_transient_unit(&name, properties).await?;
+
+		let key = scope.key(&name);
+		let watch = manager.watch_unit(&name, key.clone(), self.events.clone()).await?;
+		let run = RunningUnit {
+			template: template.into(),
+			scope,
+			name,
+			watch,
+			reply,
+		};
+		info!("started {} from {}", run.name, template);
+		self
+			.publish_run(&run, RunStatus {
+				template,
+				unit: &run.name,
+				event_type: "started",
+				result: None,
+				exit_code: None,
+				exit_status: None,
+			})
+			.await?;
+		self.runs.insert(key.clone(), run);
+
+		// it may have exited before we started watching
+		self.check_run(&key).await
 	}
 
-	pub async fn inform_unit(&self, unit: &Unit<'c>, unit_proxy: &UnitProxy<'_>) -> Result<()> {
+	/// Publish a transient unit's outcome once it has exited, and let systemd forget about it
+	async fn check_run(&mut self, key: &str) -> Result<()> {
+		let run = match self.runs.get(key) {
+			Some(run) => run,
+			None => return Ok(()),
+		};
+		let state = run.watch.proxy.active_state().await?;
+		// a unit that hasn't started yet is inactive too, but has no invocation
+		if !matches!(&state[..], "inactive" | "failed") || run.watch.proxy.invocation_id().await?.is_empty() {
+			return Ok(())
+		}
+		let run = match self.runs.remove(key) {
+			Some(run) => run,
+			None => return Ok(()),
+		};
+		let manager = self
+			.managers
+			.get(&run.scope)
+			.ok_or_else(|| format_err!("not connected to {} manager", run.scope))?;
+
+		let (result, code, status) = manager.service_result(&run.watch.proxy).await?;
+		let exit_code = match code {
+			1 => Some("exited"),
+			2 => Some("killed"),
+			3 => Some("dumped"),
+			_ => None,
+		};
+		info!("{} {}", run.name, result);
+		self
+			.publish_run(&run, RunStatus {
+				template: &run.template,
+				unit: &run.name,
+				event_type: "exited",
+				result: Some(result),
+				exit_code,
+				exit_status: exit_code.map(|_| status),
+			})
+			.await?;
+		if let Err(e) = manager.proxy.unref_unit(run.name.clone()).await {
+			warn!("Failed to release {}: {:?}", run.name, e);
+		}
+
+		Ok(())
+	}
+
+	async fn publish_run(&self, run: &RunningUnit, status: RunStatus<'_>) -> Result<()> {
+		if self.cli.use_mqtt() {
+			let payload = status.encode();
+			if let Some(reply) = &run.reply {
+				self.mqtt.publish(reply.message(&payload[..])?).await?;
+			}
+			self
+				.mqtt
+				.publish(Message::new(self.cli.mqtt_run_topic(), payload, QOS))
+				.await?;
+		}
+
+		Ok(())
+	}
+
+	pub async fn inform_units(&self) -> Result<()> {
+		futures::future::try_join_all(self.watches.keys().map(|key| self.inform_unit(key))).await?;
+
+		Ok(())
+	}
+
+	/// Publish a unit's state after it changed, which may race with its manager going away,
+	/// such as when its user logs out or its container stops
+	async fn update_unit(&self, key: &str) {
+		if let Err(e) = self.inform_unit(key).await {
+			error!("Failed to update {}: {:?}", key, e);
+		}
+	}
+
+	pub async fn inform_unit(&self, key: &str) -> Result<()> {
+		let (unit, unit_proxy) = match (self.units.get(key), self.watches.get(key)) {
+			(Some(unit), Some(watch)) => (unit, &watch.proxy),
+			_ => {
+				info!("uninterested in unit {}", key);
+				return Ok(())
+			},
+		};
+
+		let load_state = unit_proxy.load_state().await?;
+		let resources = match self.managers.get(&unit.scope) {
+			Some(manager) =>
+				manager
+					.resource_properties(unit_proxy, unit.unit_name(), &unit.unit.properties)
+					.await?,
+			None => Default::default(),
+		};
 		let payload = UnitStatus {
-			load_state: unit_proxy.load_state().await?,
+			masked: load_state == "masked",
+			// older managers lack the property altogether
+			freezer_state: match unit.unit.pause {
+				true => Some(unit_proxy.freezer_state().await?),
+				false => None,
+			},
+			resources,
+			load_state,
 			active_state: unit_proxy.active_state().await?,
 			id: unit_proxy.id().await?,
 			invocation_id: unit_proxy.invocation_id().await?,
 			description: unit_proxy.description().await?,
 			transient: unit_proxy.transient().await?,
+			need_daemon_reload: self.need_daemon_reload.contains(key),
+			unit_file_state: match (unit.unit.enable, self.managers.get(&unit.scope)) {
+				// not a property, as systemd doesn't signal changes to it
+				(Some(..), Some(manager)) => manager.proxy.get_unit_file_state(unit.unit_name().into()).await.ok(),
+				_ => None,
+			},
 		};
 
 		if self.cli.use_mqtt() {
@@ -178,44 +1135,335 @@ impl<'c> Core<'c> {
 		Ok(())
 	}
 
-	pub async fn handle_activate(&self, manager: &ManagerProxy<'_>, unit: &str, payload: &[u8]) -> Result<()> {
-		let mode = "fail".into();
-		match serde_json::from_slice::<UnitCommand>(payload) {
-			Ok(UnitCommand::Start) => {
-				manager.start_unit(unit.into(), mode).await?;
+	/// Run a unit command, returning the job it enqueued if any
+	pub async fn handle_activate(&self, unit: &Unit<'c>, request: &UnitRequest) -> Result<Option<OwnedObjectPath>> {
+		if unit.unit.read_only {
+			return Err(format_err!("{} is read-only", unit.key()))
+		}
+		let manager = &self
+			.managers
+			.get(&unit.scope)
+			.ok_or_else(|| format_err!("not connected to {} manager", unit.scope))?
+			.proxy;
+		let instance = match &request.instance {
+			Some(instance) => Some(unit.unit.payload_instance(instance)?),
+			None if unit.unit.is_payload_template() => return Err(format_err!("{} needs an instance to act on", unit.key())),
+			None => None,
+		};
+		let name = instance.as_ref().unwrap_or_else(|| unit.unit_name());
+		let mode = match request.mode {
+			Some(..) if !request.command.is_job() =>
+				return Err(format_err!("{:?} does not accept a job mode", request.command)),
+			Some(mode) if !unit.unit.allows_job_mode(mode) =>
+				return Err(format_err!("job mode {} is not allowed for {}", mode, unit.key())),
+			Some(mode) => mode,
+			None => unit.unit.job_mode(),
+		};
+		let mode = mode.to_string();
+		let job = match &request.command {
+			UnitCommand::Start => Some(manager.start_unit(name.into(), mode).await?),
+			UnitCommand::Stop => Some(manager.stop_unit(name.into(), mode).await?),
+			UnitCommand::Restart => Some(manager.restart_unit(name.into(), mode).await?),
+			UnitCommand::Reload => Some(manager.reload_unit(name.into(), mode).await?),
+			UnitCommand::TryRestart => Some(manager.try_restart_unit(name.into(), mode).await?),
+			UnitCommand::ReloadOrRestart => Some(manager.reload_or_restart_unit(name.into(), mode).await?),
+			UnitCommand::ReloadOrTryRestart => Some(manager.reload_or_try_restart_unit(name.into(), mode).await?),
+			UnitCommand::ResetFailed => {
+				manager.reset_failed_unit(name.into()).await?;
+				None
 			},
-			Ok(UnitCommand::Stop) => {
-				manager.stop_unit(unit.into(), mode).await?;
+			UnitCommand::Enable | UnitCommand::Disable if unit.unit.enable != Some(EnableEntity::Switch) =>
+				return Err(format_err!("enabling and disabling is not allowed for {}", unit.key())),
+			UnitCommand::Enable => {
+				manager.enable_unit_files(vec![name.into()], false, false).await?;
+				manager.reload().await?;
+				None
+			},
+			UnitCommand::Disable => {
+				manager.disable_unit_files(vec![name.into()], false).await?;
+				manager.reload().await?;
+				None
+			},
+			UnitCommand::Mask | UnitCommand::Unmask => {
+				let runtime = match unit.unit.mask {
+					Some(mode) => mode.is_runtime(),
+					None => return Err(format_err!("masking is not allowed for {}", unit.key())),
+				};
+				match request.command {
+					UnitCommand::Mask => {
+						manager.mask_unit_files(vec![name.into()], runtime, false).await?;
+					},
+					_ => {
+						manager.unmask_unit_files(vec![name.into()], runtime).await?;
+					},
+				}
+				manager.reload().await?;
+				None
 			},
-			Ok(UnitCommand::Restart) => {
-				manager.restart_unit(unit.into(), mode).await?;
+			UnitCommand::Freeze | UnitCommand::Thaw if !unit.unit.pause =>
+				return Err(format_err!("pausing is not allowed for {}", unit.key())),
+			UnitCommand::Freeze => {
+				manager.freeze_unit(name.into()).await?;
+				None
+			},
+			UnitCommand::Thaw => {
+				manager.thaw_unit(name.into()).await?;
+				None
+			},
+			UnitCommand::Clean { mask } => {
+				let mask = match &mask[..] {
+					[] => &unit.unit.clean[..],
+					mask => mask,
+				};
+				if let Some(kind) = mask.iter().find(|kind| !unit.unit.clean.contains(kind)) {
+					return Err(format_err!("{} may not be cleaned for {}", kind.as_str(), unit.key()))
+				}
+				if mask.is_empty() {
+					return Err(format_err!("cleaning is not enabled for {}", unit.key()))
+				}
+				// systemd would refuse as well, but less clearly
+				if let Some(watch) = self.watches.get(&unit.key()) {
+					let state = watch.proxy.active_state().await?;
+					if !matches!(&state[..], "inactive" | "failed") {
+						return Err(format_err!(
+							"{} must be stopped to be cleaned, but is {}",
+							unit.key(),
+							state
+						))
+					}
+				}
+				let mask = mask.iter().map(|kind| kind.as_str().into()).collect();
+				manager.clean_unit(name.into(), mask).await?;
+				None
 			},
+			UnitCommand::SetProperty { property, value } => {
+				if !unit.unit.properties.contains(property) {
+					return Err(format_err!(
+						"{} may not be adjusted for {}",
+						property.as_str(),
+						unit.key()
+					))
+				}
+				let value = zvariant::Value::from(property.encode(*value));
+				manager
+					.set_unit_properties(name.into(), !unit.unit.persist_properties, vec![(
+						property.dbus_name().into(),
+						value.into(),
+					)])
+					.await?;
+				None
+			},
+			UnitCommand::Kill { whom, signal } => {
+				manager.kill_unit(name.into(), whom.clone(), *signal).await?;
+				None
+			},
+		};
+
+		Ok(job)
+	}
+
+	/// Handle a message on a unit's `activate` topic
+	async fn handle_unit_message(&mut self, key: &str, message: &Message) -> Result<()> {
+		let reply = Reply::from_message(message);
+		let unit = match self.units.get(key) {
+			Some(unit) => unit,
+			None => {
+				warn!("attempt to control untracked unit {}", key);
+				return Ok(())
+			},
+		};
+		let request = match UnitRequest::decode(message.payload()) {
+			Ok(request) => request,
 			Err(e) => {
-				warn!("unsupported unit command: {:?}", e)
+				warn!("unsupported unit command: {:?}", e);
+				let error = UnitError {
+					unit: key,
+					command: None,
+					kind: ErrorKind::Invalid,
+					error: e.to_string(),
+				};
+				return self.publish_error(&error, reply.as_ref()).await
 			},
+		};
+		let (scope, result_topic) = (unit.scope.clone(), unit.mqtt_result_topic());
+
+		match self.handle_activate(unit, &request).await {
+			Ok(Some(job)) => {
+				let job = PendingJob {
+					scope,
+					job: job.to_string(),
+					command: request.command,
+					result_topic,
+					reply,
+				};
+				self.jobs.insert((job.scope.clone(), job.job.clone()), job);
+			},
+			Ok(None) => {
+				if let Some(reply) = &reply {
+					let payload = UnitResult {
+						event_type: "done",
+						command: &request.command,
+						job_id: None,
+						result: "done",
+					};
+					self.mqtt.publish(reply.message(payload.encode())?).await?;
+				}
+				// no job signals completion, and some changes don't touch the active state
+				self.inform_unit(key).await?;
+			},
+			Err(e) => {
+				warn!("Failed to {:?} {}: {:?}", request.command, key, e);
+				let error = UnitError {
+					unit: key,
+					command: Some(&request.command),
+					kind: ErrorKind::classify(&e),
+					error: format!("{:#}", e),
+				};
+				self.publish_error(&error, reply.as_ref()).await?;
+			},
+		}
+
+		Ok(())
+	}
+
+	/// Report a failed command on the error topic, and to whoever asked for it
+	async fn publish_error(&self, error: &UnitError<'_>, reply: Option<&Reply>) -> Result<()> {
+		if self.cli.use_mqtt() {
+			let payload = error.encode();
+			if let Some(reply) = reply {
+				self.mqtt.publish(reply.message(&payload[..])?).await?;
+			}
+			self
+				.mqtt
+				.publish(Message::new_retained(self.cli.mqtt_error_topic(), payload, QOS))
+				.await?;
 		}
+
 		Ok(())
 	}
 
-	pub async fn handle_message(&self, manager: &ManagerProxy<'_>, message: &Message) -> Result<bool> {
-		let segments = message.topic().split('/').collect::<Vec<_>>();
-		match &segments[..] {
-			["systemd", hostname, ..] if *hostname != self.cli.hostname() => (), // not for us, ignore
-			[_, _, unit, "activate"] => match self.units.contains_key(unit) {
-				true => self.handle_activate(manager, unit, &message.payload()).await?,
-				false => {
-					warn!("attempt to control untracked unit {}", unit);
+	pub async fn handle_message(&mut self, message: &Message) -> Result<bool> {
+		let topic = message.topic();
+		let host_root = format!("{}/", self.cli.host_topic_root());
+		match topic.strip_prefix(&host_root[..]) {
+			None => (), // not for us, ignore
+			Some(_) if topic == self.cli.mqtt_sub_topic() =>
+				match serde_json::from_slice::<ServiceCommand>(message.payload()) {
+					Ok(ServiceCommand::Set { active }) => match active {
+						true => (), // ignore, already on
+						false => return Ok(false),
+					},
+					Ok(ServiceCommand::Track { unit, .. } | ServiceCommand::Untrack { unit }) if !self.cli.mqtt_track =>
+						warn!("ignoring request to change tracking of {}, see --mqtt-track", unit),
+					Ok(ServiceCommand::Track { unit, config }) => {
+						let config = match config {
+							Some(config) => Ok(config.with_unit(&unit[..])),
+							None => unit.parse::<UnitConfig>(),
+						};
+						match config {
+							Ok(config) => match self.track(config).await {
+								Ok(()) => info!("tracking {} on request", unit),
+								Err(e) => error!("Failed to track {}: {:?}", unit, e),
+							},
+							Err(e) => warn!("invalid unit {}: {:?}", unit, e),
+						}
+					},
+					Ok(ServiceCommand::Untrack { unit }) => match self.untrack(&unit).await {
+						Ok(true) => info!("no longer tracking {} on request", unit),
+						Ok(false) => warn!("{} was not tracked on request", unit),
+						Err(e) => error!("Failed to untrack {}: {:?}", unit, e),
+					},
+					Ok(ServiceCommand::Power { action }) => {
+						let res = match &self.power {
+							Some(power) if self.power_actions.contains(&action) => power.perform(action).await,
+							_ => Err(format_err!("{:?} is not available", action)),
+						};
+						match res {
+							Ok(()) => info!("{:?} requested via MQTT", action),
+							Err(e) => {
+								warn!("Failed to {:?}: {:?}", action, e);
+								let error = UnitError {
+									unit: action.verb(),
+									command: None,
+									kind: ErrorKind::classify(&e),
+									error: format!("{:#}", e),
+								};
+								self
+									.publish_error(&error, Reply::from_message(message).as_ref())
+									.await?;
+							},
+						}
+					},
+					Ok(ServiceCommand::Reload) => {
+						let scope = self.cli.scope();
+						let res = match self.managers.get(&scope) {
+							Some(manager) => manager.proxy.reload().await.map_err(Into::into),
+							None => Err(format_err!("not connected to {} manager", scope)),
+						};
+						match res {
+							Ok(()) => info!("reloaded {} manager on request", scope),
+							Err(e) => {
+								warn!("Failed to reload {} manager: {:?}", scope, e);
+								let error = UnitError {
+									unit: "daemon-reload",
+									command: None,
+									kind: ErrorKind::classify(&e),
+									error: format!("{:#}", e),
+								};
+								self
+									.publish_error(&error, Reply::from_message(message).as_ref())
+									.await?;
+							},
+						}
+					},
+					Ok(ServiceCommand::Isolate { target }) => match self.isolate(&target).await {
+						Ok(()) => info!("isolating {} on request", target),
+						Err(e) => {
+							warn!("Failed to isolate {}: {:?}", target, e);
+							let error = UnitError {
+								unit: &target,
+								command: None,
+								kind: ErrorKind::classify(&e),
+								error: format!("{:#}", e),
+							};
+							self
+								.publish_error(&error, Reply::from_message(message).as_ref())
+								.await?;
+						},
+					},
+					Ok(ServiceCommand::Run { template, params }) => {
+						let reply = Reply::from_message(message);
+						if let Err(e) = self.run(&template, &params, reply.clone()).await {
+							warn!("Failed to run {}: {:?}", template, e);
+							let error = UnitError {
+								unit: &template,
+								command: None,
+								kind: ErrorKind::classify(&e),
+								error: format!("{:#}", e),
+							};
+							self.publish_error(&error, reply.as_ref()).await?;
+						}
+					},
+					Err(e) => warn!("unsupported systemd2mqtt command: {:?}", e),
 				},
+			Some(_) if self.cli.mqtt_config && topic == self.cli.mqtt_config_topic() => {
+				let remote = match message.payload() {
+					[] => Ok(Default::default()), // retained configuration was cleared
+					payload => serde_json::from_slice::<UnitSelection>(payload),
+				};
+				match remote {
+					Ok(remote) => match self.set_remote(remote).await {
+						Ok(()) => info!("applied configuration from {}", topic),
+						Err(e) => error!("Failed to apply configuration from {}: {:?}", topic, e),
+					},
+					Err(e) => warn!("invalid configuration on {}: {:?}", topic, e),
+				}
 			},
-			[_, _, "control"] => match serde_json::from_slice::<ServiceCommand>(message.payload()) {
-				Ok(ServiceCommand::Set { active }) => match active {
-					true => (), // ignore, already on
-					false => return Ok(false),
+			Some(rest) => match rest.strip_suffix("/activate") {
+				Some(key) => self.handle_unit_message(key, message).await?,
+				None => {
+					warn!("unrecognized topic {}", topic);
 				},
-				Err(e) => warn!("unsupported systemd2mqtt command: {:?}", e),
-			},
-			_ => {
-				warn!("unrecognized topic {}", message.topic());
 			},
 		}
 		Ok(true)
@@ -0,0 +1,114 @@
+//! home-assistant MQTT platforms, or settings for them, that `hass_mqtt_discovery` doesn't provide
+
+use {
+	crate::payload::{Entity, JsonSerializer},
+	hass_mqtt_discovery::{Availability, Device, EntityCategory},
+	serde::Serialize,
+};
+
+/// Settings shared by every entity
+#[derive(Serialize, Debug, Clone)]
+pub struct Common<'a> {
+	pub unique_id: String,
+	pub object_id: String,
+	pub name: String,
+	pub device: Device<'a>,
+	pub availability: Vec<Availability<'a>>,
+	pub enabled_by_default: bool,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub entity_category: Option<EntityCategory>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub icon: Option<String>,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub json_attributes_topic: Option<String>,
+}
+
+/// <https://www.home-assistant.io/integrations/event.mqtt/>
+#[derive(Serialize, Debug, Clone)]
+pub struct Event<'a> {
+	#[serde(flatten)]
+	pub common: Common<'a>,
+	pub state_topic: String,
+	pub event_types: Vec<&'static str>,
+}
+
+/// <https://www.home-assistant.io/integrations/lock.mqtt/>
+#[derive(Serialize, Debug, Clone)]
+pub struct Lock<'a> {
+	#[serde(flatten)]
+	pub common: Common<'a>,
+	pub command_topic: String,
+	pub state_topic: String,
+	pub payload_lock: String,
+	pub payload_unlock: String,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub value_template: Option<String>,
+}
+
+/// <https://www.home-assistant.io/integrations/number.mqtt/>
+#[derive(Serialize, Debug, Clone)]
+pub struct Number<'a> {
+	#[serde(flatten)]
+	pub common: Common<'a>,
+	pub command_topic: String,
+	pub command_template: String,
+	pub state_topic: String,
+	pub value_template: String,
+	pub min: f64,
+	pub max: f64,
+	pub step: f64,
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub unit_of_measurement: Option<&'static str>,
+	/// `auto`, `box`, or `slider`
+	pub mode: &'static str,
+}
+
+/// <https://www.home-assistant.io/integrations/select.mqtt/>
+#[derive(Serialize, Debug, Clone)]
+pub struct Select<'a> {
+	#[serde(flatten)]
+	pub common: Common<'a>,
+	pub command_topic: String,
+	pub command_template: String,
+	pub state_topic: String,
+	pub value_template: String,
+	pub options: Vec<String>,
+}
+
+/// A `binary_sensor` with the `problem` device class, which `hass_mqtt_discovery` lacks
+#[derive(Serialize, Debug, Clone)]
+pub struct ProblemSensor<'a> {
+	#[serde(flatten)]
+	pub common: Common<'a>,
+	pub state_topic: String,
+	pub value_template: String,
+	pub device_class: &'static str,
+}
+
+macro_rules! impl_entity {
+	($($ty:ident = $platform:literal,)*) => {
+		$(
+			impl<'a> Entity for $ty<'a> {
+				fn platform(&self) -> &'static str {
+					$platform
+				}
+
+				fn unique_id(&self) -> &str {
+					&self.common.unique_id
+				}
+
+				fn serialize_json(&self, serializer: &mut JsonSerializer) -> Result<(), serde_json::Error> {
+					Serialize::serialize(self, serializer)
+				}
+			}
+		)*
+	};
+}
+
+impl_entity! {
+	Event = "event",
+	Lock = "lock",
+	Number = "number",
+	Select = "select",
+	ProblemSensor = "binary_sensor",
+}
@@ -1,15 +1,26 @@
 use {
 	self::{cli::Args, core::Core},
 	anyhow::{format_err, Result},
-	clap::Parser,
-	futures::{pin_mut, select, FutureExt, StreamExt},
+	futures::{channel::mpsc, pin_mut, select, FutureExt, StreamExt},
 	log::{debug, error, info, trace},
 	sd_notify::NotifyState,
+	std::time::Duration,
+	tokio::{
+		signal::unix::{signal, SignalKind},
+		time::interval,
+	},
 };
 
 mod cli;
+mod config;
 mod core;
+mod entity;
+mod manager;
 mod payload;
+mod state;
+
+/// How often to look for unit files changed on disk, which systemd doesn't signal
+const DAEMON_RELOAD_CHECK_INTERVAL: Duration = Duration::from_secs(60);
 
 fn log_init() {
 	use {
@@ -39,109 +50,106 @@ fn info_notify(msg: &str) {
 async fn main() -> Result<()> {
 	log_init();
 
-	let cli = Args::parse();
+	let cli = Args::load()?;
 
-	info_notify("Connecting to system bus…");
-	let mut core = Core::new(&cli).await?;
+	let (events, mut systemd_events) = mpsc::unbounded();
+	let mut core = Core::new(&cli, events).await?;
 
 	let mut messages = core.mqtt.get_stream(25);
 
-	info_notify("Communicating with org.freedesktop.systemd1…");
-	let manager = core.sys_manager().await?;
-
 	let ctrlc = StreamExt::fuse(async_ctrlc::CtrlC::new().expect("ctrl+c"));
 	pin_mut!(ctrlc);
 
-	let units = core.unit_proxies(&manager).fuse();
-	pin_mut!(units);
-
-	let units = select! {
-		units = units => units,
-		_ = ctrlc.next() => return Ok(()),
-	};
+	let mut hangup = signal(SignalKind::hangup())?;
+	let mut hangup = futures::stream::poll_fn(move |cx| hangup.poll_recv(cx)).fuse();
 
-	let systemd_changes = futures::future::join_all(units.iter().map(|(_, (unit, proxy))| {
-		proxy
-			.receive_active_state_changed()
-			.map(move |c| c.map(move |c| (unit, proxy, c)))
-	}))
-	.fuse();
-	pin_mut!(systemd_changes);
-
-	let systemd_changes = select! {
-		res = systemd_changes => res,
-		_ = ctrlc.next() => return Ok(()),
-	};
+	{
+		let units = async {
+			info_notify("Communicating with org.freedesktop.systemd1…");
+			core.connect_managers().await?;
+			core.expand_unit_patterns().await?;
+			core.watch_units().await;
+			Ok::<(), anyhow::Error>(())
+		}
+		.fuse();
+		pin_mut!(units);
 
-	let systemd_changes = futures::stream::select_all(systemd_changes);
-	pin_mut!(systemd_changes);
+		select! {
+			res = units => res?,
+			_ = ctrlc.next() => return Ok(()),
+		};
+	}
 
 	info_notify("Connecting to MQTT broker…");
-	core.connect(&manager).await?;
+	core.connect().await?;
 
 	info_notify("Broadcasting unit entities and state…");
-	let initial_setup = async {
-		core.announce().await?;
-		let mut futures = Vec::new();
-		for (unit, proxy) in units.values() {
-			futures.push(core.inform_unit(unit, proxy));
+	let interrupted = {
+		let initial_setup = async {
+			core.announce().await?;
+			core.inform_units().await
 		}
-		futures::future::try_join_all(futures).await?;
-		Ok::<(), anyhow::Error>(())
-	}
-	.fuse();
-	pin_mut!(initial_setup);
+		.fuse();
+		pin_mut!(initial_setup);
 
-	let mut new_jobs = manager.receive_job_new().await?;
-	let mut done_jobs = manager.receive_job_removed().await?;
-
-	loop {
 		select! {
-			res = initial_setup => match res {
-				Ok(()) => {
-					info_notify("Started");
-					notify(NotifyState::Ready);
-				},
-				Err(e) =>
-					error!("Failed to perform initial setup: {:?}", e),
-			},
-			_ = ctrlc.next() => {
-				break
-			},
-			job_new = new_jobs.next() => {
-				let job_new = job_new
-					.ok_or_else(|| format_err!("lost systemd connection"))?;
-				let job_new = job_new.args()?;
-				let unit_name = job_new.unit();
-				match units.get(&unit_name[..]) {
-					Some((unit, proxy)) => core.inform_unit(unit, proxy).await?,
-					None => info!("uninterested in new {} job", unit_name),
+			res = initial_setup => {
+				match res {
+					Ok(()) => {
+						info_notify("Started");
+						notify(NotifyState::Ready);
+					},
+					Err(e) =>
+						error!("Failed to perform initial setup: {:?}", e),
 				}
+				false
 			},
-			job_removed = done_jobs.next() => {
-				let job_removed = job_removed
-					.ok_or_else(|| format_err!("lost systemd connection"))?;
-				let job_removed = job_removed.args()?;
-				let unit_name = job_removed.unit();
-				match units.get(&unit_name[..]) {
-					Some((unit, proxy)) => core.inform_unit(unit, proxy).await?,
-					None => info!("uninterested in removed {} job", unit_name),
-				}
-			},
-			res = systemd_changes.next() => if let Some((unit, proxy, _active_changed)) = res {
-				core.inform_unit(unit, proxy).await?;
-			},
-			message = messages.next() => {
-				let message = match message {
-					Some(Some(m)) => m,
-					_ => return Err(format_err!("lost mqtt connection")),
-				};
-				debug!("received MQTT msg: {:#?}", message.topic());
-				if !core.handle_message(&manager, &message).await? {
-					info!("shutdown requested via MQTT");
+			_ = ctrlc.next() => true,
+		}
+	};
+
+	if !interrupted {
+		// the first tick completes immediately
+		let mut daemon_reload_check = interval(DAEMON_RELOAD_CHECK_INTERVAL);
+		loop {
+			select! {
+				_ = ctrlc.next() => {
 					break
-				}
-			},
+				},
+				_ = hangup.next() => {
+					notify(NotifyState::Reloading);
+					info_notify("Reloading units…");
+					let res = match cli.reload_selection() {
+						Ok(selection) => core.reload(selection).await,
+						Err(e) => Err(e),
+					};
+					match res {
+						Ok(()) => info_notify("Reloaded"),
+						Err(e) => error!("Failed to reload: {:?}", e),
+					}
+					notify(NotifyState::Ready);
+				},
+				_ = daemon_reload_check.tick().fuse() =>
+					if let Err(e) = core.check_daemon_reload().await {
+						error!("Failed to check for changed unit files: {:?}", e);
+					},
+				event = systemd_events.next() => {
+					let event = event
+						.ok_or_else(|| format_err!("lost systemd connection"))?;
+					core.handle_event(event).await?;
+				},
+				message = messages.next() => {
+					let message = match message {
+						Some(Some(m)) => m,
+						_ => return Err(format_err!("lost mqtt connection")),
+					};
+					debug!("received MQTT msg: {:#?}", message.topic());
+					if !core.handle_message(&message).await? {
+						info!("shutdown requested via MQTT");
+						break
+					}
+				},
+			}
 		}
 	}
 
@@ -0,0 +1,697 @@
+use {
+	crate::cli::{ManagerKind, ResourceProperty},
+	anyhow::Result,
+	futures::{channel::mpsc, stream::BoxStream, StreamExt},
+	log::{debug, warn},
+	serde::{Deserialize, Serialize},
+	std::{collections::BTreeMap, convert::TryFrom, fmt, path::Path, time::Duration},
+	tokio::task::JoinHandle,
+	zbus_systemd::{
+		login1::{self, UserProxy},
+		machine1::{self, MachineProxy},
+		systemd1::{self, ManagerProxy, ServiceProxy, UnitProxy},
+		zbus::{self, names::InterfaceName},
+		zvariant::{OwnedObjectPath, Value},
+	},
+};
+
+/// The systemd instance that a unit is managed by
+#[derive(Debug, Clone, PartialEq, Eq, Hash, PartialOrd, Ord)]
+pub enum Scope {
+	System,
+	User(String),
+	/// The system manager of a container
+	Machine(String),
+}
+
+impl Scope {
+	pub fn kind(&self) -> ManagerKind {
+		match self {
+			Scope::System | Scope::Machine(..) => ManagerKind::System,
+			Scope::User(..) => ManagerKind::User,
+		}
+	}
+
+	/// Topic segments between the host and any units of this scope
+	pub fn topic_prefix(&self) -> Option<String> {
+		match self {
+			Scope::System => None,
+			Scope::User(user) => Some(format!("user/{user}")),
+			Scope::Machine(machine) => Some(machine.clone()),
+		}
+	}
+
+	/// Uniquely identifies a unit across all scopes, relative to the host's topic root
+	pub fn key(&self, unit: &str) -> String {
+		match self.topic_prefix() {
+			None => unit.into(),
+			Some(prefix) => format!("{prefix}/{unit}"),
+		}
+	}
+
+	/// Distinguishes this scope's home-assistant IDs from the host's
+	pub fn id_suffix(&self) -> Option<String> {
+		match self {
+			Scope::System => None,
+			Scope::User(user) => Some(format!("user_{user}")),
+			Scope::Machine(machine) => Some(format!("machine_{machine}")),
+		}
+	}
+}
+
+impl fmt::Display for Scope {
+	fn fmt(&self, f: &mut fmt::Formatter) -> fmt::Result {
+		match self {
+			Scope::System => f.write_str("system"),
+			Scope::User(user) => write!(f, "user {user}"),
+			Scope::Machine(machine) => write!(f, "machine {machine}"),
+		}
+	}
+}
+
+/// Notable things happening to a manager or the units it manages
+#[derive(Debug, Clone)]
+pub enum Event {
+	/// A tracked unit's state changed, identified by its key
+	UnitChanged(String),
+	/// The manager finished reloading its configuration
+	Reloaded(Scope),
+	/// Unit files were enabled, disabled, masked, or otherwise changed through the manager
+	UnitFilesChanged,
+	UnitNew(Scope, String),
+	UnitRemoved(Scope, String),
+	JobNew(Scope, String),
+	JobRemoved(Scope, String, JobResult),
+	/// The manager's connection went away
+	Lost(Scope),
+	/// A logged-in user's manager became reachable
+	UserNew(UserManager),
+	/// A user logged out, identified by their UID
+	UserRemoved(u32),
+	/// A container's manager became reachable
+	MachineNew(Machine),
+	MachineRemoved(String),
+}
+
+/// How a job finished, as reported by `JobRemoved`
+#[derive(Debug, Clone)]
+pub struct JobResult {
+	pub id: u32,
+	/// The job's object path
+	pub job: String,
+	/// `done`, `canceled`, `timeout`, `failed`, `dependency`, or `skipped`
+	pub result: String,
+}
+
+/// A connection to a systemd manager, forwarding its signals as [Event]s
+#[derive(Debug)]
+pub struct Manager {
+	pub conn: zbus::Connection,
+	pub proxy: ManagerProxy<'static>,
+	task: JoinHandle<()>,
+}
+
+impl Manager {
+	pub async fn new(scope: Scope, conn: zbus::Connection, events: mpsc::UnboundedSender<Event>) -> Result<Self> {
+		let proxy = ManagerProxy::new(&conn).await?;
+		proxy.subscribe().await?;
+
+		let signals: [BoxStream<'static, zbus::Result<Event>>; 6] = [
+			{
+				let scope = scope.clone();
+				proxy
+					.receive_unit_new()
+					.await?
+					.map(move |s| s.args().map(|s| Event::UnitNew(scope.clone(), s.id().into())))
+					.boxed()
+			},
+			{
+				let scope = scope.clone();
+				proxy
+					.receive_unit_removed()
+					.await?
+					.map(move |s| s.args().map(|s| Event::UnitRemoved(scope.clone(), s.id().into())))
+					.boxed()
+			},
+			{
+				let scope = scope.clone();
+				proxy
+					.receive_job_new()
+					.await?
+					.map(move |s| s.args().map(|s| Event::JobNew(scope.clone(), s.unit().into())))
+					.boxed()
+			},
+			{
+				let scope = scope.clone();
+				proxy
+					.receive_reloading()
+					.await?
+					.filter_map(move |s| {
+						// also sent as the reload starts
+						let event = match s.args() {
+							Ok(s) if *s.active() => None,
+							res => Some(res.map(|_| Event::Reloaded(scope.clone()))),
+						};
+						futures::future::ready(event)
+					})
+					.boxed()
+			},
+			proxy
+				.receive_unit_files_changed()
+				.await?
+				.map(|_| Ok(Event::UnitFilesChanged))
+				.boxed(),
+			{
+				let scope = scope.clone();
+				proxy
+					.receive_job_removed()
+					.await?
+					.map(move |s| {
+						s.args().map(|s| {
+							let result = JobResult {
+								id: *s.id(),
+								job: s.job().to_string(),
+								result: s.result().into(),
+							};
+							Event::JobRemoved(scope.clone(), s.unit().into(), result)
+						})
+					})
+					.boxed()
+			},
+		];
+		let mut signals = futures::stream::select_all(signals);
+
+		let task = tokio::spawn(async move {
+			while let Some(event) = signals.next().await {
+				match event {
+					Ok(event) =>
+						if events.unbounded_send(event).is_err() {
+							return
+						},
+					Err(e) => warn!("unexpected signal from {} manager: {:?}", scope, e),
+				}
+			}
+			let _ = events.unbounded_send(Event::Lost(scope));
+		});
+
+		Ok(Self { conn, proxy, task })
+	}
+
+	pub async fn unit_proxy(&self, unit: &str) -> Result<UnitProxy<'static>> {
+		Ok(
+			UnitProxy::builder(&self.conn)
+				.path(self.proxy.load_unit(unit.into()).await?)?
+				.build()
+				.await?,
+		)
+	}
+
+	/// Create and start a transient unit, which `zbus_systemd` has no binding for
+	pub async fn start_transient_unit(&self, name: &str, properties: Vec<(&str, Value<'_>)>) -> Result<OwnedObjectPath> {
+		let aux: Vec<(&str, Vec<(&str, Value)>)> = Vec::new();
+		Ok(
+			self
+				.proxy
+				.inner()
+				.call("StartTransientUnit", &(name, "fail", properties, aux))
+				.await?,
+		)
+	}
+
+	/// How a service's main process went: its `Result`, `ExecMainCode`, and `ExecMainStatus`
+	pub async fn service_result(&self, unit: &UnitProxy<'_>) -> Result<(String, i32, i32)> {
+		let service = ServiceProxy::builder(&self.conn)
+			.path(unit.inner().path().to_owned())?
+			.build()
+			.await?;
+		Ok((
+			service.result().await?,
+			service.exec_main_code().await?,
+			service.exec_main_status().await?,
+		))
+	}
+
+	/// Reads properties directly, as the unit proxy's cache misses changes to those systemd doesn't
+	/// signal
+	async fn properties_proxy<'p>(&self, unit: &'p UnitProxy<'_>) -> Result<zbus::fdo::PropertiesProxy<'p>> {
+		Ok(
+			zbus::fdo::PropertiesProxy::builder(&self.conn)
+				.destination("org.freedesktop.systemd1")?
+				.path(unit.inner().path())?
+				.cache_properties(zbus::CacheProperties::No)
+				.build()
+				.await?,
+		)
+	}
+
+	/// Whether a unit's files changed on disk since the manager last loaded them
+	pub async fn need_daemon_reload(&self, unit: &UnitProxy<'_>) -> Result<bool> {
+		let value = self
+			.properties_proxy(unit)
+			.await?
+			.get(
+				InterfaceName::from_static_str("org.freedesktop.systemd1.Unit")?,
+				"NeedDaemonReload",
+			)
+			.await?;
+		Ok(bool::try_from(value)?)
+	}
+
+	/// Read a unit's resource controls, which live on the interface specific to its type
+	pub async fn resource_properties(
+		&self,
+		unit: &UnitProxy<'_>,
+		name: &str,
+		properties: &[ResourceProperty],
+	) -> Result<BTreeMap<&'static str, u64>> {
+		let mut values = BTreeMap::new();
+		let interface = match name.rsplit('.').next() {
+			Some("service") => "org.freedesktop.systemd1.Service",
+			Some("slice") => "org.freedesktop.systemd1.Slice",
+			Some("scope") => "org.freedesktop.systemd1.Scope",
+			Some("socket") => "org.freedesktop.systemd1.Socket",
+			Some("mount") => "org.freedesktop.systemd1.Mount",
+			Some("swap") => "org.freedesktop.systemd1.Swap",
+			_ => return Ok(values), // no cgroup to control
+		};
+		if properties.is_empty() {
+			return Ok(values)
+		}
+
+		let proxy = self.properties_proxy(unit).await?;
+		for property in properties {
+			let value = proxy
+				.get(InterfaceName::from_static_str(interface)?, property.dbus_name())
+				.await?;
+			values.insert(property.key(), property.decode(u64::try_from(value)?));
+		}
+
+		Ok(values)
+	}
+
+	/// Follow a unit's state, announcing changes under the given key
+	pub async fn watch_unit(&self, unit: &str, key: String, events: mpsc::UnboundedSender<Event>) -> Result<UnitWatch> {
+		let proxy = self.unit_proxy(unit).await?;
+		// freezing leaves the active state alone
+		let mut changes = futures::stream::select(
+			proxy.receive_active_state_changed().await.map(drop),
+			proxy.receive_freezer_state_changed().await.map(drop),
+		);
+		let task = tokio::spawn(async move {
+			while changes.next().await.is_some() {
+				if events.unbounded_send(Event::UnitChanged(key.clone())).is_err() {
+					break
+				}
+			}
+		});
+
+		Ok(UnitWatch { proxy, task })
+	}
+}
+
+impl Drop for Manager {
+	fn drop(&mut self) {
+		self.task.abort()
+	}
+}
+
+/// A tracked unit's D-Bus proxy, along with the task forwarding its state changes
+#[derive(Debug)]
+pub struct UnitWatch {
+	pub proxy: UnitProxy<'static>,
+	task: JoinHandle<()>,
+}
+
+impl Drop for UnitWatch {
+	fn drop(&mut self) {
+		self.task.abort()
+	}
+}
+
+/// The service manager of a user known to logind
+#[derive(Debug, Clone)]
+pub struct UserManager {
+	pub uid: u32,
+	pub name: String,
+	pub runtime_path: String,
+}
+
+impl UserManager {
+	pub async fn new(conn: &zbus::Connection, path: zbus::zvariant::OwnedObjectPath) -> Result<Self> {
+		let user = UserProxy::builder(conn).path(path)?.build().await?;
+		Ok(Self {
+			uid: user.uid().await?,
+			name: user.name().await?,
+			runtime_path: user.runtime_path().await?,
+		})
+	}
+
+	pub fn scope(&self) -> Scope {
+		Scope::User(self.name.clone())
+	}
+
+	/// The `systemd --user` private socket, which root is allowed to talk to directly
+	pub fn socket_path(&self) -> String {
+		format!("{}/systemd/private", self.runtime_path)
+	}
+
+	/// Wait for the manager to come up after the user logs in
+	pub async fn wait(&self) -> Result<()> {
+		wait_socket(&self.socket_path()).await
+	}
+
+	pub async fn connect(&self) -> Result<zbus::Connection> {
+		connect_socket(&self.socket_path()).await
+	}
+}
+
+/// Wait for a manager's private socket to show up
+async fn wait_socket(path: &str) -> Result<()> {
+	const ATTEMPTS: usize = 120;
+	for _ in 0..ATTEMPTS {
+		if Path::new(path).exists() {
+			return Ok(())
+		}
+		tokio::time::sleep(Duration::from_millis(250)).await;
+	}
+	Err(anyhow::format_err!("timed out waiting for {}", path))
+}
+
+/// Talk directly to a manager over its private socket, bypassing any bus
+async fn connect_socket(path: &str) -> Result<zbus::Connection> {
+	zbus::ConnectionBuilder::address(&format!("unix:path={}", path)[..])?
+		.p2p()
+		.build()
+		.await
+		.map_err(Into::into)
+}
+
+/// Follows users logging in and out through systemd-logind
+#[derive(Debug)]
+pub struct Logind {
+	pub conn: zbus::Connection,
+	pub proxy: login1::ManagerProxy<'static>,
+	task: JoinHandle<()>,
+}
+
+impl Logind {
+	pub async fn new(events: mpsc::UnboundedSender<Event>) -> Result<Self> {
+		let conn = zbus::Connection::system().await?;
+		let proxy = login1::ManagerProxy::new(&conn).await?;
+
+		let mut user_new = proxy.receive_user_new().await?;
+		let mut user_removed = proxy.receive_user_removed().await?;
+		let task = {
+			let conn = conn.clone();
+			tokio::spawn(async move {
+				loop {
+					let event = futures::select! {
+						s = user_new.next() => match s.map(|s| s.args().map(|s| s.object_path().clone())) {
+							Some(Ok(path)) => {
+								let user = async {
+									let user = UserManager::new(&conn, path).await?;
+									user.wait().await?;
+									Ok::<_, anyhow::Error>(user)
+								};
+								match user.await {
+									Ok(user) => Event::UserNew(user),
+									Err(e) => {
+										warn!("Failed to follow new user: {:?}", e);
+										continue
+									},
+								}
+							},
+							Some(Err(e)) => {
+								warn!("unexpected signal from logind: {:?}", e);
+								continue
+							},
+							None => break,
+						},
+						s = user_removed.next() => match s.map(|s| s.args().map(|s| *s.uid())) {
+							Some(Ok(uid)) => Event::UserRemoved(uid),
+							Some(Err(e)) => {
+								warn!("unexpected signal from logind: {:?}", e);
+								continue
+							},
+							None => break,
+						},
+					};
+					if events.unbounded_send(event).is_err() {
+						break
+					}
+				}
+				debug!("no longer following logind users");
+			})
+		};
+
+		Ok(Self { conn, proxy, task })
+	}
+
+	pub async fn users(&self) -> Result<Vec<UserManager>> {
+		let users = self.proxy.list_users().await?;
+		futures::future::try_join_all(
+			users
+				.into_iter()
+				.map(|(_uid, _name, path)| UserManager::new(&self.conn, path)),
+		)
+		.await
+	}
+}
+
+impl Drop for Logind {
+	fn drop(&mut self) {
+		self.task.abort()
+	}
+}
+
+/// A container registered with systemd-machined
+#[derive(Debug, Clone)]
+pub struct Machine {
+	pub name: String,
+	pub class: String,
+	pub leader: u32,
+}
+
+impl Machine {
+	pub async fn new(conn: &zbus::Connection, path: zbus::zvariant::OwnedObjectPath) -> Result<Self> {
+		let machine = MachineProxy::builder(conn).path(path)?.build().await?;
+		Ok(Self {
+			name: machine.name().await?,
+			class: machine.class().await?,
+			leader: machine.leader().await?,
+		})
+	}
+
+	pub fn scope(&self) -> Scope {
+		Scope::Machine(self.name.clone())
+	}
+
+	/// Only containers share a kernel that their manager can be reached through
+	pub fn is_container(&self) -> bool {
+		self.class == "container"
+	}
+
+	/// The container's manager socket, as seen through its leader's root
+	pub fn socket_path(&self) -> String {
+		format!("/proc/{}/root/run/systemd/private", self.leader)
+	}
+
+	/// Wait for the container to finish booting far enough to talk to
+	pub async fn wait(&self) -> Result<()> {
+		wait_socket(&self.socket_path()).await
+	}
+
+	pub async fn connect(&self) -> Result<zbus::Connection> {
+		connect_socket(&self.socket_path()).await
+	}
+}
+
+/// Follows containers coming and going through systemd-machined
+#[derive(Debug)]
+pub struct Machined {
+	pub conn: zbus::Connection,
+	pub proxy: machine1::ManagerProxy<'static>,
+	task: JoinHandle<()>,
+}
+
+impl Machined {
+	pub async fn new(events: mpsc::UnboundedSender<Event>) -> Result<Self> {
+		let conn = zbus::Connection::system().await?;
+		let proxy = machine1::ManagerProxy::new(&conn).await?;
+
+		let mut machine_new = proxy.receive_machine_new().await?;
+		let mut machine_removed = proxy.receive_machine_removed().await?;
+		let task = {
+			let conn = conn.clone();
+			tokio::spawn(async move {
+				loop {
+					let event = futures::select! {
+						s = machine_new.next() => match s.map(|s| s.args().map(|s| s.path().clone())) {
+							Some(Ok(path)) => {
+								let machine = async {
+									let machine = Machine::new(&conn, path).await?;
+									if machine.is_container() {
+										machine.wait().await?;
+									}
+									Ok::<_, anyhow::Error>(machine)
+								};
+								match machine.await {
+									Ok(machine) if machine.is_container() => Event::MachineNew(machine),
+									Ok(..) => continue,
+									Err(e) => {
+										warn!("Failed to follow new machine: {:?}", e);
+										continue
+									},
+								}
+							},
+							Some(Err(e)) => {
+								warn!("unexpected signal from machined: {:?}", e);
+								continue
+							},
+							None => break,
+						},
+						s = machine_removed.next() => match s.map(|s| s.args().map(|s| s.machine().clone())) {
+							Some(Ok(name)) => Event::MachineRemoved(name),
+							Some(Err(e)) => {
+								warn!("unexpected signal from machined: {:?}", e);
+								continue
+							},
+							None => break,
+						},
+					};
+					if events.unbounded_send(event).is_err() {
+						break
+					}
+				}
+				debug!("no longer following machined containers");
+			})
+		};
+
+		Ok(Self { conn, proxy, task })
+	}
+
+	/// Every running container
+	pub async fn machines(&self) -> Result<Vec<Machine>> {
+		let machines = self.proxy.list_machines().await?;
+		let machines = futures::future::try_join_all(
+			machines
+				.into_iter()
+				.map(|(_name, _class, _service, path)| Machine::new(&self.conn, path)),
+		)
+		.await?;
+		Ok(machines.into_iter().filter(Machine::is_container).collect())
+	}
+}
+
+impl Drop for Machined {
+	fn drop(&mut self) {
+		self.task.abort()
+	}
+}
+
+/// Host power management performed through logind
+#[derive(Debug, Copy, Clone, PartialEq, Eq, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case")]
+pub enum PowerAction {
+	Reboot,
+	PowerOff,
+	Suspend,
+	Hibernate,
+	HybridSleep,
+	/// Restart userspace only, see `systemd-soft-reboot.service(8)`
+	SoftReboot,
+}
+
+impl PowerAction {
+	pub const ALL: [Self; 6] = [
+		PowerAction::Reboot,
+		PowerAction::PowerOff,
+		PowerAction::Suspend,
+		PowerAction::Hibernate,
+		PowerAction::HybridSleep,
+		PowerAction::SoftReboot,
+	];
+	/// `SD_LOGIND_SOFT_REBOOT`
+	const SOFT_REBOOT: u64 = 1 << 2;
+
+	/// Identifies the action in entity names and IDs
+	pub fn verb(&self) -> &'static str {
+		match self {
+			PowerAction::Reboot => "reboot",
+			PowerAction::PowerOff => "power_off",
+			PowerAction::Suspend => "suspend",
+			PowerAction::Hibernate => "hibernate",
+			PowerAction::HybridSleep => "hybrid_sleep",
+			PowerAction::SoftReboot => "soft_reboot",
+		}
+	}
+
+	pub fn icon(&self) -> &'static str {
+		match self {
+			PowerAction::Reboot | PowerAction::SoftReboot => "mdi:restart",
+			PowerAction::PowerOff => "mdi:power",
+			PowerAction::Suspend | PowerAction::HybridSleep => "mdi:power-sleep",
+			PowerAction::Hibernate => "mdi:snowflake",
+		}
+	}
+}
+
+/// A connection to logind for managing the host's power
+#[derive(Debug)]
+pub struct Power {
+	pub conn: zbus::Connection,
+	pub proxy: login1::ManagerProxy<'static>,
+}
+
+impl Power {
+	pub async fn new() -> Result<Self> {
+		let conn = zbus::Connection::system().await?;
+		let proxy = login1::ManagerProxy::new(&conn).await?;
+		Ok(Self { conn, proxy })
+	}
+
+	/// Whether logind would carry out an action without asking for authorization,
+	/// which requests never get to answer as they aren't interactive
+	pub async fn can(&self, action: PowerAction) -> Result<bool> {
+		let answer = match action {
+			PowerAction::Reboot => self.proxy.can_reboot().await?,
+			PowerAction::PowerOff => self.proxy.can_power_off().await?,
+			PowerAction::Suspend => self.proxy.can_suspend().await?,
+			PowerAction::Hibernate => self.proxy.can_hibernate().await?,
+			PowerAction::HybridSleep => self.proxy.can_hybrid_sleep().await?,
+			PowerAction::SoftReboot => {
+				// there's no CanSoftReboot, so go by whether logind is new enough to know about it
+				let version = systemd1::ManagerProxy::new(&self.conn).await?.version().await?;
+				let major = version.split(|c: char| !c.is_ascii_digit()).next().unwrap_or_default();
+				match major.parse::<u32>() {
+					Ok(major) if major >= 254 => self.proxy.can_reboot().await?,
+					_ => return Ok(false),
+				}
+			},
+		};
+		Ok(answer == "yes")
+	}
+
+	/// Every action that [Power::can] perform
+	pub async fn available(&self) -> Result<Vec<PowerAction>> {
+		let mut actions = Vec::new();
+		for action in PowerAction::ALL {
+			if self.can(action).await? {
+				actions.push(action);
+			}
+		}
+		Ok(actions)
+	}
+
+	pub async fn perform(&self, action: PowerAction) -> Result<()> {
+		match action {
+			PowerAction::Reboot => self.proxy.reboot(false).await?,
+			PowerAction::PowerOff => self.proxy.power_off(false).await?,
+			PowerAction::Suspend => self.proxy.suspend(false).await?,
+			PowerAction::Hibernate => self.proxy.hibernate(false).await?,
+			PowerAction::HybridSleep => self.proxy.hybrid_sleep(false).await?,
+			PowerAction::SoftReboot => self.proxy.reboot_with_flags(PowerAction::SOFT_REBOOT).await?,
+		}
+		Ok(())
+	}
+}
@@ -1,8 +1,13 @@
 use {
-	crate::cli::{Args, Unit},
-	hass_mqtt_discovery::{Availability, BinarySensor, Button, Device, Document, EntityCategory, Switch},
+	crate::{
+		cli::{Args, CleanMask, EnableEntity, JobMode, ResourceProperty, Unit, UnitConfig},
+		entity::{self, Common},
+		manager::{PowerAction, Scope},
+	},
+	hass_mqtt_discovery::{Availability, BinarySensor, Button, Device, Document, EntityCategory, Sensor, Switch},
 	serde::{Deserialize, Serialize},
-	std::{borrow::Cow, fmt::Debug},
+	std::{borrow::Cow, collections::BTreeMap, fmt::Debug},
+	zbus_systemd::zbus,
 };
 
 const ON: &'static str = "ON";
@@ -13,6 +18,8 @@ pub struct ServiceStatus<'a> {
 	pub is_active: bool,
 	#[serde(borrow)]
 	pub units: Vec<Cow<'a, str>>,
+	/// Units whose files changed since systemd loaded them
+	pub need_daemon_reload: Vec<Cow<'a, str>>,
 }
 
 impl ServiceStatus<'_> {
@@ -23,7 +30,35 @@ impl ServiceStatus<'_> {
 
 #[derive(Serialize, Deserialize, Debug)]
 pub enum ServiceCommand {
-	Set { active: bool },
+	Set {
+		active: bool,
+	},
+	/// Start exposing a unit, given either as a `unit?setting=value` string or with separate settings
+	Track {
+		unit: String,
+		#[serde(default, skip_serializing_if = "Option::is_none")]
+		config: Option<UnitConfig>,
+	},
+	/// Stop exposing a unit previously added through [ServiceCommand::Track]
+	Untrack {
+		unit: String,
+	},
+	/// Reboot, power off, or suspend the host
+	Power {
+		action: PowerAction,
+	},
+	/// Reload the configuration of the primary manager
+	Reload,
+	/// Switch to one of the configured `isolate-targets`
+	Isolate {
+		target: String,
+	},
+	/// Start a transient unit from one of the configured run templates
+	Run {
+		template: String,
+		#[serde(default)]
+		params: BTreeMap<String, String>,
+	},
 }
 
 impl ServiceCommand {
@@ -40,6 +75,17 @@ pub struct UnitStatus {
 	pub invocation_id: Vec<u8>,
 	pub description: String,
 	pub transient: bool,
+	pub need_daemon_reload: bool,
+	/// Only looked up for units with `enable` set
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub unit_file_state: Option<String>,
+	pub masked: bool,
+	/// `running`, `freezing`, `frozen`, or `thawing`, only looked up for units with `pause` set
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub freezer_state: Option<String>,
+	/// The unit's `properties`, keyed by [ResourceProperty::key]
+	#[serde(skip_serializing_if = "BTreeMap::is_empty")]
+	pub resources: BTreeMap<&'static str, u64>,
 }
 
 impl UnitStatus {
@@ -48,28 +94,320 @@ impl UnitStatus {
 	}
 }
 
-#[derive(Serialize, Deserialize, Debug)]
+#[derive(Serialize, Deserialize, Debug, Clone)]
 pub enum UnitCommand {
 	Start,
 	Stop,
 	Restart,
+	Reload,
+	TryRestart,
+	ReloadOrRestart,
+	ReloadOrTryRestart,
+	ResetFailed,
+	/// Start the unit on boot, reloading the manager afterwards
+	Enable,
+	Disable,
+	/// Link the unit file to `/dev/null` as the unit's `mask` setting says,
+	/// reloading the manager afterwards
+	Mask,
+	Unmask,
+	/// Suspend the unit's processes through the cgroup freezer
+	Freeze,
+	Thaw,
+	/// Remove some of the unit's directories, defaulting to all of those it allows
+	Clean {
+		#[serde(default)]
+		mask: Vec<CleanMask>,
+	},
+	/// Adjust one of the unit's `properties`
+	SetProperty {
+		property: ResourceProperty,
+		value: u64,
+	},
+	Kill {
+		/// `main`, `control`, or `all` of the unit's processes
+		#[serde(default = "default_kill_whom")]
+		whom: String,
+		#[serde(default = "default_kill_signal")]
+		signal: i32,
+	},
+}
+
+fn default_kill_whom() -> String {
+	"all".into()
+}
+
+fn default_kill_signal() -> i32 {
+	15 // SIGTERM
+}
+
+/// The outcome of a job enqueued by a [UnitCommand]
+#[derive(Serialize, Debug)]
+pub struct UnitResult<'a> {
+	/// Mirrors `result` for home-assistant's event entity
+	pub event_type: &'a str,
+	pub command: &'a UnitCommand,
+	/// Absent for commands that take effect immediately rather than through a job
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub job_id: Option<u32>,
+	pub result: &'a str,
+}
+
+impl UnitResult<'_> {
+	/// Every `result` that systemd reports
+	pub const RESULTS: [&'static str; 6] = ["done", "failed", "timeout", "canceled", "dependency", "skipped"];
+
+	pub fn encode(&self) -> String {
+		serde_json::to_string(self).unwrap()
+	}
+}
+
+/// Which of the `isolate-targets` the host is in
+#[derive(Serialize, Debug)]
+pub struct TargetStatus<'a> {
+	pub target: &'a str,
+}
+
+impl TargetStatus<'_> {
+	pub fn encode(&self) -> String {
+		serde_json::to_string(self).unwrap()
+	}
+}
+
+/// Progress of a transient unit started through [ServiceCommand::Run]
+#[derive(Serialize, Debug)]
+pub struct RunStatus<'a> {
+	pub template: &'a str,
+	pub unit: &'a str,
+	/// `started` or `exited`
+	pub event_type: &'static str,
+	/// systemd's `Result` for the service, once exited
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub result: Option<String>,
+	/// whether the main process `exited`, or was `killed` or `dumped` core
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub exit_code: Option<&'static str>,
+	/// the exit status, or the signal that ended the main process
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub exit_status: Option<i32>,
+}
+
+impl RunStatus<'_> {
+	pub fn encode(&self) -> String {
+		serde_json::to_string(self).unwrap()
+	}
+}
+
+/// Why a [UnitCommand] couldn't be carried out
+#[derive(Serialize, Debug)]
+pub struct UnitError<'a> {
+	/// The unit's key, or what a [ServiceCommand] was about
+	pub unit: &'a str,
+	/// Absent if the command couldn't be understood
+	#[serde(skip_serializing_if = "Option::is_none")]
+	pub command: Option<&'a UnitCommand>,
+	pub kind: ErrorKind,
+	pub error: String,
+}
+
+/// Broad classes of [UnitError], so that automations needn't parse messages
+#[derive(Serialize, Debug, Clone, Copy, PartialEq, Eq)]
+#[serde(rename_all = "snake_case")]
+pub enum ErrorKind {
+	/// Refused by polkit or the D-Bus policy
+	AccessDenied,
+	NoSuchUnit,
+	/// The job couldn't be reconciled with those already queued
+	JobConflict,
+	/// Rejected before reaching systemd
+	Invalid,
+	Other,
+}
+
+impl ErrorKind {
+	pub fn classify(e: &anyhow::Error) -> Self {
+		let name = match e.downcast_ref::<zbus::Error>() {
+			Some(zbus::Error::MethodError(name, ..)) => name,
+			Some(..) => return ErrorKind::Other,
+			None => return ErrorKind::Invalid,
+		};
+		match name.as_str() {
+			"org.freedesktop.DBus.Error.AccessDenied" | "org.freedesktop.DBus.Error.InteractiveAuthorizationRequired" =>
+				ErrorKind::AccessDenied,
+			"org.freedesktop.systemd1.NoSuchUnit" | "org.freedesktop.systemd1.LoadFailed" => ErrorKind::NoSuchUnit,
+			"org.freedesktop.systemd1.TransactionIsDestructive"
+			| "org.freedesktop.systemd1.TransactionJobsConflicting"
+			| "org.freedesktop.systemd1.TransactionOrderIsCyclic"
+			| "org.freedesktop.systemd1.JobTypeNotApplicable"
+			| "org.freedesktop.systemd1.OnlyByDependency" => ErrorKind::JobConflict,
+			_ => ErrorKind::Other,
+		}
+	}
+}
+
+impl UnitError<'_> {
+	pub fn encode(&self) -> String {
+		serde_json::to_string(self).unwrap()
+	}
+}
+
+/// A [UnitCommand] along with the job mode it asks for,
+/// such as `"Start"` or `{"Start": {"mode": "replace"}}`
+#[derive(Debug)]
+pub struct UnitRequest {
+	pub command: UnitCommand,
+	pub mode: Option<JobMode>,
+	/// The instance of an `instances=payload` template to act on
+	pub instance: Option<String>,
+}
+
+impl UnitRequest {
+	pub fn decode(payload: &[u8]) -> serde_json::Result<Self> {
+		let mut value: serde_json::Value = serde_json::from_slice(payload)?;
+		let (verb, mode, instance) = match &mut value {
+			serde_json::Value::Object(command) if command.len() == 1 => match command.iter_mut().next() {
+				Some((verb, serde_json::Value::Object(options))) =>
+					(verb.clone(), options.remove("mode"), options.remove("instance")),
+				_ =>
+					return Ok(Self {
+						command: serde_json::from_value(value)?,
+						mode: None,
+						instance: None,
+					}),
+			},
+			_ =>
+				return Ok(Self {
+					command: serde_json::from_value(value)?,
+					mode: None,
+					instance: None,
+				}),
+		};
+		let mode = mode.map(serde_json::from_value).transpose()?;
+		let instance = instance.map(serde_json::from_value).transpose()?;
+		// unit variants only accept the bare string form
+		let command = serde_json::from_value(value).or_else(|e| match mode.is_some() || instance.is_some() {
+			true => serde_json::from_value(serde_json::Value::String(verb)),
+			false => Err(e),
+		})?;
+
+		Ok(Self {
+			command,
+			mode,
+			instance,
+		})
+	}
 }
 
 impl UnitCommand {
 	pub fn encode(&self) -> String {
 		serde_json::to_string(self).unwrap()
 	}
+
+	/// Whether the command enqueues a job, which a [JobMode] applies to
+	pub fn is_job(&self) -> bool {
+		!matches!(
+			self,
+			UnitCommand::ResetFailed
+				| UnitCommand::Enable
+				| UnitCommand::Disable
+				| UnitCommand::Mask
+				| UnitCommand::Unmask
+				| UnitCommand::Freeze
+				| UnitCommand::Thaw
+				| UnitCommand::Clean { .. }
+				| UnitCommand::SetProperty { .. }
+				| UnitCommand::Kill { .. }
+		)
+	}
+
+	/// Commands exposed as buttons alongside controllable units
+	pub fn buttons() -> Vec<Self> {
+		vec![
+			UnitCommand::Restart,
+			UnitCommand::Reload,
+			UnitCommand::TryRestart,
+			UnitCommand::ReloadOrRestart,
+			UnitCommand::ReloadOrTryRestart,
+			UnitCommand::ResetFailed,
+			UnitCommand::Kill {
+				whom: default_kill_whom(),
+				signal: default_kill_signal(),
+			},
+		]
+	}
+
+	/// Identifies the command in entity names and IDs
+	pub fn verb(&self) -> &'static str {
+		match self {
+			UnitCommand::Start => "start",
+			UnitCommand::Stop => "stop",
+			UnitCommand::Restart => "restart",
+			UnitCommand::Reload => "reload",
+			UnitCommand::TryRestart => "try_restart",
+			UnitCommand::ReloadOrRestart => "reload_or_restart",
+			UnitCommand::ReloadOrTryRestart => "reload_or_try_restart",
+			UnitCommand::ResetFailed => "reset_failed",
+			UnitCommand::Enable => "enable",
+			UnitCommand::Disable => "disable",
+			UnitCommand::Mask => "mask",
+			UnitCommand::Unmask => "unmask",
+			UnitCommand::Freeze => "freeze",
+			UnitCommand::Thaw => "thaw",
+			UnitCommand::Clean { .. } => "clean",
+			UnitCommand::SetProperty { .. } => "set_property",
+			UnitCommand::Kill { .. } => "kill",
+		}
+	}
+
+	pub fn icon(&self) -> Option<&'static str> {
+		match self {
+			UnitCommand::Restart | UnitCommand::TryRestart => Some("mdi:restart"),
+			UnitCommand::Reload | UnitCommand::ReloadOrRestart | UnitCommand::ReloadOrTryRestart => Some("mdi:reload"),
+			UnitCommand::ResetFailed => Some("mdi:alert-remove"),
+			UnitCommand::Kill { .. } => Some("mdi:skull"),
+			UnitCommand::Mask => Some("mdi:lock"),
+			UnitCommand::Unmask => Some("mdi:lock-open-variant"),
+			UnitCommand::Freeze => Some("mdi:pause"),
+			UnitCommand::Thaw => Some("mdi:play"),
+			UnitCommand::Clean { .. } => Some("mdi:broom"),
+			UnitCommand::Start
+			| UnitCommand::Stop
+			| UnitCommand::Enable
+			| UnitCommand::Disable
+			| UnitCommand::SetProperty { .. } => None,
+		}
+	}
 }
 
 impl Args {
 	pub fn hass_device(&self) -> Device {
+		self.hass_scope_device(&self.scope())
+	}
+
+	pub fn hass_scope_device<'d>(&self, scope: &Scope) -> Device<'d> {
+		let (name, via_device) = match scope {
+			Scope::System => (self.hostname().into_owned(), None),
+			Scope::User(user) => (
+				format!("{}@{}", user, self.hostname()),
+				Some(format!("{}-{}", env!("CARGO_PKG_NAME"), self.hostname()).into()),
+			),
+			Scope::Machine(machine) => (
+				machine.clone(),
+				Some(format!("{}-{}", env!("CARGO_PKG_NAME"), self.hostname()).into()),
+			),
+		};
 		Device {
-			identifiers: self.hass_device_identifiers().into_iter().map(|id| id.into()).collect(),
+			identifiers: self
+				.hass_scope_identifiers(scope)
+				.into_iter()
+				.map(|id| id.into())
+				.collect(),
 			manufacturer: Some(env!("CARGO_PKG_AUTHORS").into()),
 			model: Some(env!("CARGO_PKG_NAME").into()),
-			name: Some(self.hostname().to_string().into()),
+			name: Some(name.into()),
 			sw_version: Some(env!("CARGO_PKG_VERSION").into()),
 			configuration_url: Some(env!("CARGO_PKG_HOMEPAGE").into()),
+			via_device,
 			// hw_version: Some(version),
 			..Default::default()
 		}
@@ -102,6 +440,95 @@ impl Args {
 			.entity_category(EntityCategory::Diagnostic)
 	}
 
+	pub fn hass_power_button(&self, action: PowerAction) -> Button<'_> {
+		let id = format!("{}_{}", self.hass_device_id(), action.verb());
+		let mut button = Button::new(self.mqtt_sub_topic())
+			.unique_id(id.clone())
+			.object_id(id)
+			.name(format!("{} {}", self.hostname(), action.verb().replace('_', " ")))
+			.device(self.hass_device())
+			.availability(vec![self.hass_availability()])
+			.payload_press(ServiceCommand::Power { action }.encode());
+		button.icon = Some(action.icon().into());
+		button
+	}
+
+	pub fn hass_isolate_select(&self) -> entity::Select<'_> {
+		let id = format!("{}_target", self.hass_device_id());
+		entity::Select {
+			common: Common {
+				unique_id: id.clone(),
+				object_id: id,
+				name: format!("{} target", self.hostname()),
+				device: self.hass_device(),
+				availability: vec![self.hass_availability()],
+				enabled_by_default: true,
+				entity_category: Some(EntityCategory::Config),
+				icon: Some("mdi:target".into()),
+				json_attributes_topic: None,
+			},
+			command_topic: self.mqtt_sub_topic(),
+			command_template: "{\"Isolate\":{\"target\":\"{{ value }}\"}}".into(),
+			state_topic: self.mqtt_target_topic(),
+			value_template: "{{ value_json.target }}".into(),
+			options: self.isolate_targets.clone(),
+		}
+	}
+
+	pub fn hass_reload_button(&self) -> Button<'_> {
+		let id = format!("{}_daemon_reload", self.hass_device_id());
+		Button::new(self.mqtt_sub_topic())
+			.unique_id(id.clone())
+			.object_id(id)
+			.name(format!("{} daemon reload", self.hostname()))
+			.device(self.hass_device())
+			.availability(vec![self.hass_availability()])
+			.payload_press(ServiceCommand::Reload.encode())
+			.icon("mdi:reload")
+			.entity_category(EntityCategory::Config)
+	}
+
+	/// Whether any tracked unit awaits a daemon reload
+	pub fn hass_reload_sensor(&self) -> entity::ProblemSensor<'_> {
+		let id = format!("{}_need_daemon_reload", self.hass_device_id());
+		entity::ProblemSensor {
+			common: Common {
+				unique_id: id.clone(),
+				object_id: id,
+				name: format!("{} needs daemon reload", self.hostname()),
+				device: self.hass_device(),
+				availability: vec![self.hass_availability()],
+				enabled_by_default: true,
+				entity_category: Some(EntityCategory::Diagnostic),
+				icon: None,
+				json_attributes_topic: None,
+			},
+			state_topic: self.mqtt_pub_topic(),
+			value_template: "\
+				{% if value_json.need_daemon_reload %}ON\
+				{% else %}OFF\
+				{% endif %}"
+				.into(),
+			device_class: "problem",
+		}
+	}
+
+	/// Shows the most recent [UnitError]
+	pub fn hass_error_sensor(&self) -> Sensor<'_> {
+		let id = format!("{}_error", self.hass_device_id());
+		let mut sensor = Sensor::new(self.mqtt_error_topic())
+			.unique_id(id.clone())
+			.object_id(id)
+			.name(format!("{} last error", env!("CARGO_PKG_NAME")))
+			.device(self.hass_device())
+			.availability(vec![self.hass_availability()])
+			.json_attributes_topic(self.mqtt_error_topic())
+			.value_template("{{ value_json.error | truncate(255) }}")
+			.entity_category(EntityCategory::Diagnostic);
+		sensor.icon = Some("mdi:alert-circle-outline".into());
+		sensor
+	}
+
 	pub fn hass_announce(&self, config: &dyn Entity, retain: bool) -> Result<paho_mqtt::Message, serde_json::Error> {
 		let payload = config.to_json()?;
 		let new = if retain {
@@ -121,15 +548,72 @@ impl Args {
 	}
 
 	pub fn hass_device_id(&self) -> String {
-		format!("{}_{}", env!("CARGO_PKG_NAME"), self.hostname())
+		self.scope_device_id(&self.scope())
 	}
 
-	pub fn hass_device_identifiers(&self) -> impl IntoIterator<Item = String> {
-		vec!["name".into(), format!("{}-{}", env!("CARGO_PKG_NAME"), self.hostname())]
+	pub fn scope_device_id(&self, scope: &Scope) -> String {
+		match scope.id_suffix() {
+			Some(suffix) => format!("{}_{}_{}", env!("CARGO_PKG_NAME"), self.hostname(), suffix),
+			None => format!("{}_{}", env!("CARGO_PKG_NAME"), self.hostname()),
+		}
+	}
+
+	pub fn hass_scope_identifiers(&self, scope: &Scope) -> Vec<String> {
+		match scope.id_suffix() {
+			Some(suffix) => vec![format!("{}-{}-{}", env!("CARGO_PKG_NAME"), self.hostname(), suffix)],
+			None => vec!["name".into(), format!("{}-{}", env!("CARGO_PKG_NAME"), self.hostname())],
+		}
 	}
 }
 
 impl<'a> Unit<'a> {
+	const ENABLED_TEMPLATE: &'static str = "\
+		{% if value_json.unit_file_state in ['enabled', 'enabled-runtime', 'linked', 'linked-runtime', 'alias'] %}ON\
+		{% else %}OFF\
+		{% endif %}";
+
+	/// Every entity exposed for this unit, starting with its primary switch or sensor
+	pub fn to_hass_configs(&self) -> Vec<Box<dyn Entity + 'a>> {
+		if self.unit.is_payload_template() {
+			// there's no unit to show the state of until commands name one
+			return vec![Box::new(self.hass_config_result())]
+		}
+		let mut configs = vec![self.to_hass_config()];
+		match self.unit.enable {
+			Some(EnableEntity::Switch) => configs.push(Box::new(self.hass_config_enable_switch())),
+			Some(EnableEntity::BinarySensor) => configs.push(Box::new(self.hass_config_enable_sensor())),
+			None => (),
+		}
+		if self.unit.mask.is_some() {
+			configs.push(Box::new(self.hass_config_mask()));
+		}
+		if self.unit.pause {
+			configs.push(Box::new(self.hass_config_pause()));
+		}
+		if !self.unit.clean.is_empty() {
+			let command = UnitCommand::Clean {
+				mask: self.unit.clean.clone(),
+			};
+			configs.push(Box::new(self.hass_config_button(command)));
+		}
+		configs.extend(
+			self
+				.unit
+				.properties
+				.iter()
+				.map(|&property| Box::new(self.hass_config_property(property)) as Box<_>),
+		);
+		if !self.unit.read_only {
+			configs.push(Box::new(self.hass_config_result()));
+			configs.extend(
+				UnitCommand::buttons()
+					.into_iter()
+					.map(|command| Box::new(self.hass_config_button(command)) as Box<_>),
+			);
+		}
+		configs
+	}
+
 	pub fn to_hass_config(&self) -> Box<dyn Entity + 'a> {
 		match self.hass_platform() {
 			"switch" => Box::new(self.hass_config_switch()) as Box<_>,
@@ -145,12 +629,12 @@ impl<'a> Unit<'a> {
 		};
 		let mut switch = Switch::new(self.mqtt_sub_topic())
 			.unique_id(self.unique_id())
-			.object_id(self.object_id())
+			.object_id(self.object_id().into_owned())
 			.entity_category(self.entity_category())
 			.device_class(self.device_class)
 			.enabled_by_default(self.enabled_by_default)
-			.name(self.name())
-			.device(self.cli.hass_device())
+			.name(self.name().into_owned())
+			.device(self.cli.hass_scope_device(&self.scope))
 			.availability(vec![self.hass_availability()])
 			.json_attributes_topic(self.mqtt_pub_topic())
 			.state_topic(self.mqtt_pub_topic())
@@ -164,7 +648,7 @@ impl<'a> Unit<'a> {
 				{% else %}OFF\
 				{% endif %}",
 			);
-		switch.icon = self.icon().map(|s| s[..].into());
+		switch.icon = self.icon().map(|s| s.clone().into());
 		switch
 	}
 
@@ -175,12 +659,12 @@ impl<'a> Unit<'a> {
 		};
 		let mut sensor = BinarySensor::new(self.mqtt_pub_topic())
 			.unique_id(self.unique_id())
-			.object_id(self.object_id())
+			.object_id(self.object_id().into_owned())
 			.entity_category(self.entity_category())
 			.device_class(self.device_class)
 			.enabled_by_default(self.enabled_by_default)
-			.name(self.name())
-			.device(self.cli.hass_device())
+			.name(self.name().into_owned())
+			.device(self.cli.hass_scope_device(&self.scope))
 			.availability(vec![self.hass_availability()])
 			.json_attributes_topic(self.mqtt_pub_topic())
 			.payload_on(on)
@@ -191,10 +675,167 @@ impl<'a> Unit<'a> {
 				{% else %}OFF\
 				{% endif %}",
 			);
-		sensor.icon = self.icon().map(|s| s[..].into());
+		sensor.icon = self.icon().map(|s| s.clone().into());
+		sensor
+	}
+
+	pub fn hass_config_button<'s>(&'s self, command: UnitCommand) -> Button<'a> {
+		let verb = command.verb();
+		// only the most common or explicitly configured commands are shown without being enabled first
+		let enabled = matches!(
+			command,
+			UnitCommand::Restart | UnitCommand::Reload | UnitCommand::Clean { .. }
+		);
+		let mut button = Button::new(self.mqtt_sub_topic())
+			.unique_id(format!("{}_{}", self.unique_id(), verb))
+			.object_id(format!("{}_{}", self.object_id(), verb))
+			.entity_category(EntityCategory::Config)
+			.enabled_by_default(self.enabled_by_default && enabled)
+			.name(format!("{} {}", self.name(), verb.replace('_', " ")))
+			.device(self.cli.hass_scope_device(&self.scope))
+			.availability(vec![self.hass_availability()])
+			.payload_press(command.encode());
+		button.icon = command.icon().map(Into::into);
+		button
+	}
+
+	pub fn hass_config_enable_switch<'s>(&'s self) -> Switch<'a> {
+		let mut switch = Switch::new(self.mqtt_sub_topic())
+			.unique_id(format!("{}_enable", self.unique_id()))
+			.object_id(format!("{}_enable", self.object_id()))
+			.entity_category(EntityCategory::Config)
+			.enabled_by_default(self.enabled_by_default)
+			.name(format!("{} start on boot", self.name()))
+			.device(self.cli.hass_scope_device(&self.scope))
+			.availability(vec![self.hass_availability()])
+			.state_topic(self.mqtt_pub_topic())
+			.payload_on(UnitCommand::Enable.encode())
+			.payload_off(UnitCommand::Disable.encode())
+			.state_on(ON)
+			.state_off(OFF)
+			.value_template(Self::ENABLED_TEMPLATE);
+		switch.icon = Some("mdi:power-settings".into());
+		switch
+	}
+
+	pub fn hass_config_enable_sensor<'s>(&'s self) -> BinarySensor<'a> {
+		let mut sensor = BinarySensor::new(self.mqtt_pub_topic())
+			.unique_id(format!("{}_enable", self.unique_id()))
+			.object_id(format!("{}_enable", self.object_id()))
+			.entity_category(EntityCategory::Diagnostic)
+			.enabled_by_default(self.enabled_by_default)
+			.name(format!("{} start on boot", self.name()))
+			.device(self.cli.hass_scope_device(&self.scope))
+			.availability(vec![self.hass_availability()])
+			.payload_on(ON)
+			.payload_off(OFF)
+			.value_template(Self::ENABLED_TEMPLATE);
+		sensor.icon = Some("mdi:power-settings".into());
 		sensor
 	}
 
+	pub fn hass_config_pause<'s>(&'s self) -> Switch<'a> {
+		let mut switch = Switch::new(self.mqtt_sub_topic())
+			.unique_id(format!("{}_pause", self.unique_id()))
+			.object_id(format!("{}_pause", self.object_id()))
+			.entity_category(EntityCategory::Config)
+			.enabled_by_default(self.enabled_by_default)
+			.name(format!("{} paused", self.name()))
+			.device(self.cli.hass_scope_device(&self.scope))
+			.availability(vec![self.hass_availability()])
+			.state_topic(self.mqtt_pub_topic())
+			.payload_on(UnitCommand::Freeze.encode())
+			.payload_off(UnitCommand::Thaw.encode())
+			.state_on(ON)
+			.state_off(OFF)
+			.value_template(
+				"\
+				{% if value_json.freezer_state in ['frozen', 'freezing'] %}ON\
+				{% else %}OFF\
+				{% endif %}",
+			);
+		switch.icon = Some("mdi:pause".into());
+		switch
+	}
+
+	pub fn hass_config_mask(&self) -> entity::Lock<'a> {
+		entity::Lock {
+			common: Common {
+				entity_category: Some(EntityCategory::Config),
+				icon: Some("mdi:lock".into()),
+				..self.hass_common("mask", "masked")
+			},
+			command_topic: self.mqtt_sub_topic(),
+			state_topic: self.mqtt_pub_topic(),
+			payload_lock: UnitCommand::Mask.encode(),
+			payload_unlock: UnitCommand::Unmask.encode(),
+			value_template: Some(
+				"\
+				{% if value_json.masked %}LOCKED\
+				{% else %}UNLOCKED\
+				{% endif %}"
+					.into(),
+			),
+		}
+	}
+
+	pub fn hass_config_property(&self, property: ResourceProperty) -> entity::Number<'a> {
+		let cpus = std::thread::available_parallelism().map(|n| n.get()).unwrap_or(1);
+		let (max, unit_of_measurement, icon) = match property {
+			ResourceProperty::CpuQuota => (100.0 * cpus as f64, Some("%"), "mdi:cpu-64-bit"),
+			ResourceProperty::CpuWeight => (10000.0, None, "mdi:cpu-64-bit"),
+			ResourceProperty::MemoryHigh | ResourceProperty::MemoryMax => (1048576.0, Some("MiB"), "mdi:memory"),
+			ResourceProperty::IoWeight => (10000.0, None, "mdi:harddisk"),
+			ResourceProperty::TasksMax => (4194304.0, None, "mdi:format-list-numbered"),
+		};
+		let key = property.key();
+		entity::Number {
+			common: Common {
+				entity_category: Some(EntityCategory::Config),
+				icon: Some(icon.into()),
+				..self.hass_common(key, &key.replace('_', " "))
+			},
+			command_topic: self.mqtt_sub_topic(),
+			command_template: format!(
+				"{{\"SetProperty\":{{\"property\":\"{}\",\"value\":{{{{ value | int }}}}}}}}",
+				property.as_str(),
+			),
+			state_topic: self.mqtt_pub_topic(),
+			value_template: format!("{{{{ value_json.resources.{} | default(0) }}}}", key),
+			min: 0.0,
+			max,
+			step: 1.0,
+			unit_of_measurement,
+			mode: "box",
+		}
+	}
+
+	/// Settings for an additional entity of this unit, distinguished by `suffix`
+	pub fn hass_common(&self, suffix: &str, name: &str) -> Common<'a> {
+		Common {
+			unique_id: format!("{}_{}", self.unique_id(), suffix),
+			object_id: format!("{}_{}", self.object_id(), suffix),
+			name: format!("{} {}", self.name(), name),
+			device: self.cli.hass_scope_device(&self.scope),
+			availability: vec![self.hass_availability()],
+			enabled_by_default: self.enabled_by_default,
+			entity_category: None,
+			icon: None,
+			json_attributes_topic: None,
+		}
+	}
+
+	pub fn hass_config_result(&self) -> entity::Event<'a> {
+		entity::Event {
+			common: Common {
+				icon: Some("mdi:clipboard-check-outline".into()),
+				..self.hass_common("result", "result")
+			},
+			state_topic: self.mqtt_result_topic(),
+			event_types: UnitResult::RESULTS.to_vec(),
+		}
+	}
+
 	pub fn hass_availability(&self) -> Availability<'static> {
 		Availability {
 			topic: self.cli.mqtt_pub_topic().into(),
@@ -206,7 +847,7 @@ impl<'a> Unit<'a> {
 					{{% if value_json.is_active and '{}' in value_json.units %}}ON\
 					{{% else %}}OFF\
 					{{% endif %}}",
-					self.unit_name(),
+					self.key(),
 				)
 				.into(),
 			),
@@ -214,7 +855,7 @@ impl<'a> Unit<'a> {
 	}
 }
 
-type JsonSerializer<'w> = serde_json::Serializer<&'w mut Vec<u8>>;
+pub type JsonSerializer<'w> = serde_json::Serializer<&'w mut Vec<u8>>;
 
 pub trait Entity: Debug {
 	fn unique_id(&self) -> &str;
@@ -230,16 +871,37 @@ pub trait Entity: Debug {
 }
 
 impl<'a> Unit<'a> {
+	pub fn hass_configs<'u>(&'u self) -> &'u [Box<dyn Entity + 'a>] {
+		self.config.get_or_init(|| self.to_hass_configs())
+	}
+
 	pub fn hass_config<'u>(&'u self) -> &'u (dyn Entity + 'a) {
-		self.config.get_or_init(|| self.to_hass_config()).as_ref()
+		self.hass_configs()[0].as_ref()
 	}
 
-	pub fn hass_announce(&self, retain: bool) -> serde_json::Result<paho_mqtt::Message> {
-		self.cli.hass_announce(self.hass_config(), retain)
+	pub fn hass_announce(&self, retain: bool) -> serde_json::Result<Vec<paho_mqtt::Message>> {
+		self
+			.hass_configs()
+			.iter()
+			.map(|config| self.cli.hass_announce(config.as_ref(), retain))
+			.collect()
 	}
 
-	pub fn hass_config_topic(&self) -> String {
-		self.cli.hass_config_topic_with(self.platform(), &self.unique_id())
+	pub fn hass_config_topics(&self) -> Vec<String> {
+		self
+			.hass_configs()
+			.iter()
+			.map(|config| self.cli.hass_config_topic(config.as_ref()))
+			.collect()
+	}
+
+	/// Remove this unit's entities from home-assistant
+	pub fn hass_retract(&self) -> Vec<paho_mqtt::Message> {
+		self
+			.hass_config_topics()
+			.into_iter()
+			.map(|topic| paho_mqtt::Message::new_retained(topic, "", paho_mqtt::QOS_0))
+			.collect()
 	}
 }
 
@@ -282,3 +944,51 @@ impl_entity! {
 	Switch = "switch", Button = "button",
 	Sensor = "sensor", BinarySensor = "binary_sensor",
 }
+
+#[cfg(test)]
+mod tests {
+	use super::*;
+
+	#[test]
+	fn decode_bare_command() {
+		let request = UnitRequest::decode(br#""Start""#).unwrap();
+		assert!(matches!(request.command, UnitCommand::Start));
+		assert_eq!(request.mode, None);
+		assert_eq!(request.instance, None);
+	}
+
+	#[test]
+	fn decode_mode() {
+		let request = UnitRequest::decode(br#"{"Start": {"mode": "replace"}}"#).unwrap();
+		assert!(matches!(request.command, UnitCommand::Start));
+		assert_eq!(request.mode, Some(JobMode::Replace));
+	}
+
+	#[test]
+	fn decode_mode_alongside_fields() {
+		let request = UnitRequest::decode(br#"{"Kill": {"signal": 9, "mode": "replace"}}"#).unwrap();
+		assert!(matches!(request.command, UnitCommand::Kill { signal: 9, .. }));
+		assert_eq!(request.mode, Some(JobMode::Replace));
+	}
+
+	#[test]
+	fn decode_instance() {
+		let request = UnitRequest::decode(br#"{"Start": {"mode": "replace", "instance": "nas"}}"#).unwrap();
+		assert!(matches!(request.command, UnitCommand::Start));
+		assert_eq!(request.mode, Some(JobMode::Replace));
+		assert_eq!(request.instance.as_deref(), Some("nas"));
+	}
+
+	#[test]
+	fn decode_instance_alongside_fields() {
+		let request = UnitRequest::decode(br#"{"Kill": {"signal": 9, "instance": "nas"}}"#).unwrap();
+		assert!(matches!(request.command, UnitCommand::Kill { signal: 9, .. }));
+		assert_eq!(request.mode, None);
+		assert_eq!(request.instance.as_deref(), Some("nas"));
+	}
+
+	#[test]
+	fn decode_rejects_unknown_mode() {
+		assert!(UnitRequest::decode(br#"{"Start": {"mode": "whenever"}}"#).is_err());
+	}
+}
@@ -0,0 +1,65 @@
+use {
+	crate::cli::{Args, UnitConfig},
+	anyhow::{Context, Result},
+	serde::{Deserialize, Serialize},
+	std::{fs, io, path::PathBuf},
+};
+
+/// Changes made at runtime through the control topic, persisted across restarts
+#[derive(Debug, Default, Clone, Serialize, Deserialize)]
+#[serde(rename_all = "kebab-case", default)]
+pub struct State {
+	/// units added with [crate::payload::ServiceCommand::Track]
+	pub units: Vec<UnitConfig>,
+}
+
+impl State {
+	/// Where state is kept, if anywhere
+	pub fn path(cli: &Args) -> Option<PathBuf> {
+		cli.state_directory.as_ref().map(|dir| dir.join("state.json"))
+	}
+
+	/// Units tracked at runtime, unless tracking has since been turned off
+	pub fn tracked(&self, cli: &Args) -> &[UnitConfig] {
+		match cli.mqtt_track {
+			true => &self.units,
+			false => &[],
+		}
+	}
+
+	pub fn load(cli: &Args) -> Result<Self> {
+		let path = match Self::path(cli) {
+			Some(path) => path,
+			None => return Ok(Self::default()),
+		};
+		match fs::read(&path) {
+			Ok(data) => serde_json::from_slice(&data).with_context(|| format!("failed to parse {}", path.display())),
+			Err(e) if e.kind() == io::ErrorKind::NotFound => Ok(Self::default()),
+			Err(e) => Err(e).with_context(|| format!("failed to read {}", path.display())),
+		}
+	}
+
+	pub fn save(&self, cli: &Args) -> Result<()> {
+		let path = match Self::path(cli) {
+			Some(path) => path,
+			None => return Ok(()),
+		};
+		// write to the side first so that a crash can't leave a truncated file behind
+		let temp = path.with_extension("json.tmp");
+		fs::write(&temp, serde_json::to_vec_pretty(self)?)
+			.and_then(|()| fs::rename(&temp, &path))
+			.with_context(|| format!("failed to write {}", path.display()))
+	}
+
+	/// Remember a unit, replacing any previous settings for it
+	pub fn track(&mut self, unit: UnitConfig) {
+		self.untrack(&unit.unit);
+		self.units.push(unit);
+	}
+
+	pub fn untrack(&mut self, unit: &str) -> bool {
+		let len = self.units.len();
+		self.units.retain(|u| u.unit != unit);
+		self.units.len() != len
+	}
+}